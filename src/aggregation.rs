@@ -0,0 +1,166 @@
+use std::ops::Range;
+
+use anyhow::{ensure, Result};
+use ethers::prelude::*;
+use plonky2::field::goldilocks_field::GoldilocksField;
+use plonky2::plonk::config::PoseidonGoldilocksConfig;
+use plonky2::plonk::proof::ProofWithPublicInputs;
+use plonky2::util::timing::TimingTree;
+use plonky2_evm::all_stark::{AllStark, NUM_TABLES};
+use plonky2_evm::config::StarkConfig;
+use plonky2_evm::fixed_recursive_verifier::AllRecursiveCircuits;
+use plonky2_evm::proof::PublicValues;
+
+use crate::txns::{IntermediateState, TxnInputs};
+
+/// Recursive circuits verifying transaction proofs, aggregating them, and wrapping them into block proofs.
+pub type RecursiveCircuits = AllRecursiveCircuits<GoldilocksField, PoseidonGoldilocksConfig, 2>;
+
+/// Proof of one of the recursive circuits.
+pub type RecursiveProof = ProofWithPublicInputs<GoldilocksField, PoseidonGoldilocksConfig, 2>;
+
+/// Range of the degree bits of each STARK table supported by the recursive circuits, in the order of the tables in
+/// `AllStark`: arithmetic, CPU, Keccak, Keccak sponge, logic and memory. Wide enough for the transactions of mainnet
+/// blocks, at the cost of building one circuit per degree.
+const DEGREE_BITS_RANGES: [Range<usize>; NUM_TABLES] =
+    [16..25, 15..25, 14..22, 9..21, 12..21, 17..27];
+
+/// Recursive proof of a whole block, aggregating the proofs of its transactions.
+pub struct AggregatedBlockProof {
+    pub proof: RecursiveProof,
+    pub public_values: PublicValues,
+    /// State before the block, i.e., the state root of the parent block.
+    pub before: IntermediateState,
+    /// State after the block.
+    pub after: IntermediateState,
+}
+
+/// Proof of a range of consecutive transactions of a block.
+struct RangeProof {
+    proof: RecursiveProof,
+    public_values: PublicValues,
+    /// Whether the proof comes from the aggregation circuit, or from the root circuit for a single transaction.
+    is_aggregation: bool,
+    before: IntermediateState,
+    after: IntermediateState,
+}
+
+/// Build the recursive circuits. This takes a long time, so the circuits should be reused for all blocks.
+pub fn recursive_circuits() -> RecursiveCircuits {
+    AllRecursiveCircuits::new(
+        &AllStark::default(),
+        &DEGREE_BITS_RANGES,
+        &StarkConfig::standard_fast_config(),
+    )
+}
+
+/// Prove each transaction of a block, and aggregate the proofs into a single block proof.
+/// The aggregation circuit checks that each transaction starts from the state root left by the previous one.
+pub fn prove_block_aggregated(
+    circuits: &RecursiveCircuits,
+    txns: Vec<TxnInputs>,
+) -> Result<AggregatedBlockProof> {
+    ensure!(!txns.is_empty(), "No transaction inputs to aggregate");
+
+    let mut txns = txns;
+    if txns.len() == 1 {
+        // The block circuit needs an aggregation proof, so a single transaction is aggregated with an empty one.
+        let mut empty = txns[0].clone();
+        empty.inputs.signed_txns = vec![];
+        empty.inputs.withdrawals = vec![];
+        empty.after = empty.before;
+        txns.insert(0, empty);
+    }
+
+    let all_stark = AllStark::default();
+    let config = StarkConfig::standard_fast_config();
+    let mut proofs = txns
+        .into_iter()
+        .map(|txn| {
+            let (proof, public_values) =
+                circuits.prove_root(&all_stark, &config, txn.inputs, &mut TimingTree::default())?;
+            circuits.verify_root(proof.clone())?;
+            check_state_roots(&public_values, txn.before, txn.after)?;
+            Ok(RangeProof {
+                proof,
+                public_values,
+                is_aggregation: false,
+                before: txn.before,
+                after: txn.after,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Aggregate neighbouring proofs until a single one is left.
+    while proofs.len() > 1 {
+        let mut next = Vec::with_capacity(proofs.len().div_ceil(2));
+        let mut proofs_iter = proofs.into_iter();
+        while let Some(lhs) = proofs_iter.next() {
+            match proofs_iter.next() {
+                Some(rhs) => next.push(aggregate(circuits, lhs, rhs)?),
+                None => next.push(lhs),
+            }
+        }
+        proofs = next;
+    }
+
+    let root = proofs.pop().expect("There is at least one proof");
+    ensure!(
+        root.is_aggregation,
+        "The block circuit needs an aggregation proof"
+    );
+    let (proof, public_values) = circuits.prove_block(None, &root.proof, root.public_values)?;
+    circuits.verify_block(&proof)?;
+    check_state_roots(&public_values, root.before, root.after)?;
+    Ok(AggregatedBlockProof {
+        proof,
+        public_values,
+        before: root.before,
+        after: root.after,
+    })
+}
+
+/// Aggregate the proofs of two consecutive ranges of transactions.
+fn aggregate(circuits: &RecursiveCircuits, lhs: RangeProof, rhs: RangeProof) -> Result<RangeProof> {
+    let public_values = PublicValues {
+        trie_roots_before: lhs.public_values.trie_roots_before,
+        trie_roots_after: rhs.public_values.trie_roots_after,
+        block_metadata: rhs.public_values.block_metadata,
+    };
+    let (proof, public_values) = circuits.prove_aggregation(
+        lhs.is_aggregation,
+        &lhs.proof,
+        rhs.is_aggregation,
+        &rhs.proof,
+        public_values,
+    )?;
+    circuits.verify_aggregation(&proof)?;
+    Ok(RangeProof {
+        proof,
+        public_values,
+        is_aggregation: true,
+        before: lhs.before,
+        after: rhs.after,
+    })
+}
+
+/// Check that the public values of a proof go from the `before` state root to the `after` state root.
+fn check_state_roots(
+    public_values: &PublicValues,
+    before: IntermediateState,
+    after: IntermediateState,
+) -> Result<()> {
+    let roots = (
+        public_values.trie_roots_before.state_root,
+        public_values.trie_roots_after.state_root,
+    );
+    ensure!(
+        roots == (before.state_root, after.state_root),
+        "Proof goes from state root {:?} to {:?}, expected {:?} to {:?}",
+        roots.0,
+        roots.1,
+        before.state_root,
+        after.state_root
+    );
+    Ok(())
+}
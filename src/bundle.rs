@@ -0,0 +1,377 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, ensure, Result};
+use async_trait::async_trait;
+use ethers::prelude::*;
+use plonky2_evm::generation::GenerationInputs;
+use serde::{Deserialize, Serialize};
+
+use crate::data_source::BlockDataSource;
+use crate::txns::prove_block_txns_loop;
+use crate::{get_block_inputs, prove_block_loop, ProverOptions};
+
+/// Version of the bundle file format. Bump it whenever the layout of `WitnessBundle` changes.
+pub const BUNDLE_VERSION: u32 = 3;
+
+/// All the raw RPC data needed to build the witness of a block.
+/// A bundle is itself a `BlockDataSource`, so the witness can be rebuilt from it without network access.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct WitnessBundle {
+    pub version: u32,
+    pub block_number: u64,
+    #[serde(default)]
+    pub chain_id: Option<U256>,
+    pub blocks: BTreeMap<u64, Block<TxHash>>,
+    #[serde(default)]
+    pub blocks_with_txs: BTreeMap<u64, Block<Transaction>>,
+    pub transactions: BTreeMap<TxHash, Transaction>,
+    #[serde(default)]
+    pub receipts: BTreeMap<TxHash, TransactionReceipt>,
+    #[serde(default)]
+    pub block_receipts: BTreeMap<u64, Vec<TransactionReceipt>>,
+    pub prestate_traces: BTreeMap<TxHash, BTreeMap<Address, AccountState>>,
+    #[serde(default)]
+    pub block_prestate_traces: BTreeMap<u64, Vec<BTreeMap<Address, AccountState>>>,
+    #[serde(default)]
+    pub block_state_diffs: BTreeMap<u64, Vec<DiffMode>>,
+    /// Account proofs indexed by block number and address.
+    /// Storage proofs of different requests for the same account are merged.
+    pub proofs: BTreeMap<u64, BTreeMap<Address, EIP1186ProofResponse>>,
+    /// Trie nodes indexed by hash.
+    #[serde(default)]
+    pub nodes: BTreeMap<H256, Bytes>,
+}
+
+impl WitnessBundle {
+    pub fn new(block_number: u64) -> Self {
+        Self {
+            version: BUNDLE_VERSION,
+            block_number,
+            ..Default::default()
+        }
+    }
+
+    /// Read a bundle from a JSON file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path)?;
+        let bundle: Self = serde_json::from_reader(BufReader::new(file))?;
+        ensure!(
+            bundle.version == BUNDLE_VERSION,
+            "Unsupported bundle version {}, expected {}",
+            bundle.version,
+            BUNDLE_VERSION
+        );
+        Ok(bundle)
+    }
+
+    /// Write the bundle to a JSON file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer(BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    fn insert_proof(&mut self, block_number: U64, proof: EIP1186ProofResponse) {
+        let account_proofs = self.proofs.entry(block_number.as_u64()).or_default();
+        match account_proofs.get_mut(&proof.address) {
+            Some(existing) => {
+                for sp in proof.storage_proof {
+                    if !existing.storage_proof.iter().any(|p| p.key == sp.key) {
+                        existing.storage_proof.push(sp);
+                    }
+                }
+            }
+            None => {
+                account_proofs.insert(proof.address, proof);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BlockDataSource for WitnessBundle {
+    async fn get_chain_id(&self) -> Result<U256> {
+        self.chain_id
+            .ok_or_else(|| anyhow!("Chain id not in bundle."))
+    }
+
+    async fn get_block(&self, block_number: U64) -> Result<Block<TxHash>> {
+        self.blocks
+            .get(&block_number.as_u64())
+            .cloned()
+            .ok_or_else(|| anyhow!("Block {} not in bundle.", block_number))
+    }
+
+    async fn get_block_with_txs(&self, block_number: U64) -> Result<Block<Transaction>> {
+        self.blocks_with_txs
+            .get(&block_number.as_u64())
+            .cloned()
+            .ok_or_else(|| anyhow!("Block {} with transactions not in bundle.", block_number))
+    }
+
+    async fn get_transaction(&self, hash: TxHash) -> Result<Transaction> {
+        self.transactions
+            .get(&hash)
+            .cloned()
+            .ok_or_else(|| anyhow!("Transaction {:?} not in bundle.", hash))
+    }
+
+    async fn get_transaction_receipt(&self, hash: TxHash) -> Result<TransactionReceipt> {
+        self.receipts
+            .get(&hash)
+            .cloned()
+            .ok_or_else(|| anyhow!("Receipt of transaction {:?} not in bundle.", hash))
+    }
+
+    async fn get_block_receipts(&self, block_number: U64) -> Result<Vec<TransactionReceipt>> {
+        self.block_receipts
+            .get(&block_number.as_u64())
+            .cloned()
+            .ok_or_else(|| anyhow!("Receipts of block {} not in bundle.", block_number))
+    }
+
+    async fn get_prestate_trace(&self, hash: TxHash) -> Result<BTreeMap<Address, AccountState>> {
+        self.prestate_traces
+            .get(&hash)
+            .cloned()
+            .ok_or_else(|| anyhow!("Trace of transaction {:?} not in bundle.", hash))
+    }
+
+    async fn get_block_prestate_traces(
+        &self,
+        block_number: U64,
+    ) -> Result<Vec<BTreeMap<Address, AccountState>>> {
+        self.block_prestate_traces
+            .get(&block_number.as_u64())
+            .cloned()
+            .ok_or_else(|| anyhow!("Traces of block {} not in bundle.", block_number))
+    }
+
+    async fn get_block_state_diffs(&self, block_number: U64) -> Result<Vec<DiffMode>> {
+        self.block_state_diffs
+            .get(&block_number.as_u64())
+            .cloned()
+            .ok_or_else(|| anyhow!("State diffs of block {} not in bundle.", block_number))
+    }
+
+    async fn get_proof(
+        &self,
+        address: Address,
+        locations: Vec<H256>,
+        block_number: U64,
+    ) -> Result<EIP1186ProofResponse> {
+        let proof = self
+            .proofs
+            .get(&block_number.as_u64())
+            .and_then(|m| m.get(&address))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Proof of account {:?} at block {} not in bundle.",
+                    address,
+                    block_number
+                )
+            })?;
+        let storage_proofs = proof
+            .storage_proof
+            .iter()
+            .map(|sp| (sp.key, sp))
+            .collect::<HashMap<_, _>>();
+        let storage_proof = locations
+            .into_iter()
+            .map(|key| {
+                storage_proofs
+                    .get(&key)
+                    .map(|&sp| sp.clone())
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Proof of slot {:?} of account {:?} at block {} not in bundle.",
+                            key,
+                            address,
+                            block_number
+                        )
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(EIP1186ProofResponse {
+            storage_proof,
+            ..proof.clone()
+        })
+    }
+
+    async fn get_node(&self, hash: H256) -> Result<Bytes> {
+        self.nodes
+            .get(&hash)
+            .cloned()
+            .ok_or_else(|| anyhow!("Trie node {:?} not in bundle.", hash))
+    }
+}
+
+/// Data source forwarding all requests to an inner source and recording the responses in a bundle.
+pub struct RecordingSource<'a, D> {
+    inner: &'a D,
+    bundle: Mutex<WitnessBundle>,
+}
+
+impl<'a, D: BlockDataSource> RecordingSource<'a, D> {
+    pub fn new(inner: &'a D, block_number: u64) -> Self {
+        Self {
+            inner,
+            bundle: Mutex::new(WitnessBundle::new(block_number)),
+        }
+    }
+
+    /// Return the bundle of all the responses recorded so far.
+    pub fn into_bundle(self) -> WitnessBundle {
+        self.bundle.into_inner().unwrap()
+    }
+}
+
+#[async_trait]
+impl<'a, D: BlockDataSource> BlockDataSource for RecordingSource<'a, D> {
+    async fn get_chain_id(&self) -> Result<U256> {
+        let chain_id = self.inner.get_chain_id().await?;
+        self.bundle.lock().unwrap().chain_id = Some(chain_id);
+        Ok(chain_id)
+    }
+
+    async fn get_block(&self, block_number: U64) -> Result<Block<TxHash>> {
+        let block = self.inner.get_block(block_number).await?;
+        self.bundle
+            .lock()
+            .unwrap()
+            .blocks
+            .insert(block_number.as_u64(), block.clone());
+        Ok(block)
+    }
+
+    async fn get_block_with_txs(&self, block_number: U64) -> Result<Block<Transaction>> {
+        let block = self.inner.get_block_with_txs(block_number).await?;
+        self.bundle
+            .lock()
+            .unwrap()
+            .blocks_with_txs
+            .insert(block_number.as_u64(), block.clone());
+        Ok(block)
+    }
+
+    async fn get_transaction(&self, hash: TxHash) -> Result<Transaction> {
+        let txn = self.inner.get_transaction(hash).await?;
+        self.bundle
+            .lock()
+            .unwrap()
+            .transactions
+            .insert(hash, txn.clone());
+        Ok(txn)
+    }
+
+    async fn get_transaction_receipt(&self, hash: TxHash) -> Result<TransactionReceipt> {
+        let receipt = self.inner.get_transaction_receipt(hash).await?;
+        self.bundle
+            .lock()
+            .unwrap()
+            .receipts
+            .insert(hash, receipt.clone());
+        Ok(receipt)
+    }
+
+    async fn get_block_receipts(&self, block_number: U64) -> Result<Vec<TransactionReceipt>> {
+        let receipts = self.inner.get_block_receipts(block_number).await?;
+        self.bundle
+            .lock()
+            .unwrap()
+            .block_receipts
+            .insert(block_number.as_u64(), receipts.clone());
+        Ok(receipts)
+    }
+
+    async fn get_prestate_trace(&self, hash: TxHash) -> Result<BTreeMap<Address, AccountState>> {
+        let trace = self.inner.get_prestate_trace(hash).await?;
+        self.bundle
+            .lock()
+            .unwrap()
+            .prestate_traces
+            .insert(hash, trace.clone());
+        Ok(trace)
+    }
+
+    async fn get_block_prestate_traces(
+        &self,
+        block_number: U64,
+    ) -> Result<Vec<BTreeMap<Address, AccountState>>> {
+        let traces = self.inner.get_block_prestate_traces(block_number).await?;
+        self.bundle
+            .lock()
+            .unwrap()
+            .block_prestate_traces
+            .insert(block_number.as_u64(), traces.clone());
+        Ok(traces)
+    }
+
+    async fn get_block_state_diffs(&self, block_number: U64) -> Result<Vec<DiffMode>> {
+        let diffs = self.inner.get_block_state_diffs(block_number).await?;
+        self.bundle
+            .lock()
+            .unwrap()
+            .block_state_diffs
+            .insert(block_number.as_u64(), diffs.clone());
+        Ok(diffs)
+    }
+
+    async fn get_proof(
+        &self,
+        address: Address,
+        locations: Vec<H256>,
+        block_number: U64,
+    ) -> Result<EIP1186ProofResponse> {
+        let proof = self
+            .inner
+            .get_proof(address, locations, block_number)
+            .await?;
+        self.bundle
+            .lock()
+            .unwrap()
+            .insert_proof(block_number, proof.clone());
+        Ok(proof)
+    }
+
+    async fn get_node(&self, hash: H256) -> Result<Bytes> {
+        let node = self.inner.get_node(hash).await?;
+        self.bundle.lock().unwrap().nodes.insert(hash, node.clone());
+        Ok(node)
+    }
+}
+
+/// Fetch all the data needed to build the witness of a block and collect it in a bundle.
+/// Witness generation is run on the block, so that the trie nodes it finds missing, and the proofs of the keys ground
+/// to recover them, are recorded as well. The block is not proven, regardless of `options.full_proof`.
+pub async fn fetch_bundle<D: BlockDataSource>(
+    block_number: u64,
+    source: &D,
+    options: &ProverOptions,
+) -> Result<WitnessBundle> {
+    let recorder = RecordingSource::new(source, block_number);
+    let options = ProverOptions {
+        full_proof: false,
+        ..options.clone()
+    };
+    if options.per_txn {
+        prove_block_txns_loop(block_number, &recorder, &options, None).await?;
+    } else {
+        prove_block_loop(block_number, &recorder, &options).await?;
+    }
+    Ok(recorder.into_bundle())
+}
+
+/// Build the Plonky2 generation inputs of the block contained in a bundle, without any network access.
+/// Also return the state root after the block.
+pub async fn replay_bundle(
+    bundle: &WitnessBundle,
+    options: &ProverOptions,
+) -> Result<(GenerationInputs, H256)> {
+    let (inputs, block, _) = get_block_inputs(bundle.block_number, bundle, options).await?;
+    Ok((inputs, block.state_root))
+}
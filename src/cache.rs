@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use ethers::prelude::*;
+use ethers::utils::keccak256;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::data_source::BlockDataSource;
+use crate::utils::write_json_file;
+
+/// Data source caching the responses of an inner source on disk.
+/// Each response is stored in its own file, named after the hash of the request method and parameters,
+/// so a block can be re-run without hitting the node again. Responses of different chains are stored in different
+/// subdirectories, as the same request can have different responses on each chain.
+pub struct CachedSource<D> {
+    inner: D,
+    /// Cache directory. `None` bypasses the cache and forwards all requests to the inner source.
+    dir: Option<PathBuf>,
+}
+
+impl<D: BlockDataSource> CachedSource<D> {
+    /// Cache the responses of `inner` in a subdirectory of `dir` for the chain id of the node, creating it if needed.
+    /// The chain id is fetched from the node without going through the cache.
+    pub async fn new(inner: D, dir: impl Into<PathBuf>) -> Result<Self> {
+        let chain_id = inner.get_chain_id().await?;
+        let dir = dir.into().join(format!("chain-{}", chain_id));
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            inner,
+            dir: Some(dir),
+        })
+    }
+
+    /// The inner source, to send requests that must not be answered from the cache.
+    pub fn uncached(&self) -> &D {
+        &self.inner
+    }
+
+    /// Forward all requests to `inner` without caching anything.
+    pub fn bypass(inner: D) -> Self {
+        Self { inner, dir: None }
+    }
+
+    /// Remove all cached responses.
+    pub fn clear(&self) -> Result<()> {
+        if let Some(dir) = &self.dir {
+            fs::remove_dir_all(dir)?;
+            fs::create_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Path of the cache file for the given request.
+    fn path<P: Serialize>(&self, method: &str, params: &P) -> Result<Option<PathBuf>> {
+        let Some(dir) = &self.dir else {
+            return Ok(None);
+        };
+        let key = serde_json::to_vec(&(method, params))?;
+        let name = format!("{}-{}.json", method, hex::encode(keccak256(key)));
+        Ok(Some(dir.join(name)))
+    }
+
+    /// Return the cached response for the given request, or fetch it and store it in the cache.
+    async fn cached<P, T, F>(&self, method: &str, params: P, fetch: F) -> Result<T>
+    where
+        P: Serialize,
+        T: Serialize + DeserializeOwned,
+        F: Future<Output = Result<T>>,
+    {
+        let Some(path) = self.path(method, &params)? else {
+            return fetch.await;
+        };
+        if let Ok(file) = File::open(&path) {
+            if let Ok(value) = serde_json::from_reader(BufReader::new(file)) {
+                return Ok(value);
+            }
+        }
+        let value = fetch.await?;
+        write_json_file(&path, &value)?;
+        Ok(value)
+    }
+}
+
+#[async_trait]
+impl<D: BlockDataSource> BlockDataSource for CachedSource<D> {
+    async fn get_chain_id(&self) -> Result<U256> {
+        self.cached("eth_chainId", (), self.inner.get_chain_id())
+            .await
+    }
+
+    async fn get_block(&self, block_number: U64) -> Result<Block<TxHash>> {
+        self.cached(
+            "eth_getBlockByNumber",
+            (block_number, false),
+            self.inner.get_block(block_number),
+        )
+        .await
+    }
+
+    async fn get_block_with_txs(&self, block_number: U64) -> Result<Block<Transaction>> {
+        self.cached(
+            "eth_getBlockByNumber",
+            (block_number, true),
+            self.inner.get_block_with_txs(block_number),
+        )
+        .await
+    }
+
+    async fn get_transaction(&self, hash: TxHash) -> Result<Transaction> {
+        self.cached(
+            "eth_getTransactionByHash",
+            hash,
+            self.inner.get_transaction(hash),
+        )
+        .await
+    }
+
+    async fn get_transaction_receipt(&self, hash: TxHash) -> Result<TransactionReceipt> {
+        self.cached(
+            "eth_getTransactionReceipt",
+            hash,
+            self.inner.get_transaction_receipt(hash),
+        )
+        .await
+    }
+
+    async fn get_block_receipts(&self, block_number: U64) -> Result<Vec<TransactionReceipt>> {
+        self.cached(
+            "eth_getBlockReceipts",
+            block_number,
+            self.inner.get_block_receipts(block_number),
+        )
+        .await
+    }
+
+    async fn get_prestate_trace(&self, hash: TxHash) -> Result<BTreeMap<Address, AccountState>> {
+        self.cached(
+            "debug_traceTransaction",
+            hash,
+            self.inner.get_prestate_trace(hash),
+        )
+        .await
+    }
+
+    async fn get_block_prestate_traces(
+        &self,
+        block_number: U64,
+    ) -> Result<Vec<BTreeMap<Address, AccountState>>> {
+        self.cached(
+            "debug_traceBlockByNumber",
+            block_number,
+            self.inner.get_block_prestate_traces(block_number),
+        )
+        .await
+    }
+
+    async fn get_block_state_diffs(&self, block_number: U64) -> Result<Vec<DiffMode>> {
+        self.cached(
+            "debug_traceBlockByNumber",
+            (block_number, "diffMode"),
+            self.inner.get_block_state_diffs(block_number),
+        )
+        .await
+    }
+
+    async fn get_proof(
+        &self,
+        address: Address,
+        locations: Vec<H256>,
+        block_number: U64,
+    ) -> Result<EIP1186ProofResponse> {
+        self.cached(
+            "eth_getProof",
+            (address, &locations, block_number),
+            self.inner
+                .get_proof(address, locations.clone(), block_number),
+        )
+        .await
+    }
+
+    async fn get_node(&self, hash: H256) -> Result<Bytes> {
+        self.cached("debug_dbGet", hash, self.inner.get_node(hash))
+            .await
+    }
+}
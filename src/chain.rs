@@ -0,0 +1,67 @@
+use ethers::prelude::*;
+
+/// Activation point of a hard fork. Forks after the Merge are activated at a timestamp rather than a block number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForkActivation {
+    Block(u64),
+    Timestamp(u64),
+}
+
+impl ForkActivation {
+    /// Whether the fork is active at the block with the given number and timestamp.
+    pub fn is_active(&self, block_number: u64, timestamp: u64) -> bool {
+        match *self {
+            ForkActivation::Block(b) => block_number >= b,
+            ForkActivation::Timestamp(t) => timestamp >= t,
+        }
+    }
+}
+
+/// Per-network configuration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainConfig {
+    pub chain_id: U256,
+    pub shanghai: ForkActivation,
+}
+
+impl ChainConfig {
+    pub fn mainnet() -> Self {
+        Self {
+            chain_id: U256::from(1),
+            shanghai: ForkActivation::Timestamp(1681338455),
+        }
+    }
+
+    pub fn sepolia() -> Self {
+        Self {
+            chain_id: U256::from(11155111),
+            shanghai: ForkActivation::Timestamp(1677557088),
+        }
+    }
+
+    pub fn holesky() -> Self {
+        Self {
+            chain_id: U256::from(17000),
+            shanghai: ForkActivation::Timestamp(1696000704),
+        }
+    }
+
+    /// Configuration of the chain with the given chain id.
+    /// Unknown chains, e.g. devnets, are assumed to have all supported forks active from genesis.
+    pub fn from_chain_id(chain_id: U256) -> Self {
+        [Self::mainnet(), Self::sepolia(), Self::holesky()]
+            .into_iter()
+            .find(|c| c.chain_id == chain_id)
+            .unwrap_or(Self {
+                chain_id,
+                shanghai: ForkActivation::Block(0),
+            })
+    }
+
+    /// Whether the Shanghai upgrade is active at the given block.
+    pub fn is_shanghai<TX>(&self, block: &Block<TX>) -> bool {
+        let block_number = block.number.unwrap_or_default().as_u64();
+        self.shanghai
+            .is_active(block_number, block.timestamp.as_u64())
+    }
+}
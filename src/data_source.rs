@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use ethers::prelude::*;
+use ethers::types::{
+    DiffMode, GethDebugBuiltInTracerConfig, GethDebugTracerConfig, GethDebugTracerType,
+    PreStateConfig,
+};
+use serde::{Deserialize, Serialize};
+
+/// A backend providing the raw chain data needed to build the witness of a block.
+#[async_trait]
+pub trait BlockDataSource: Send + Sync {
+    /// Get the chain id of the network.
+    async fn get_chain_id(&self) -> Result<U256>;
+
+    /// Get the block with the given block number.
+    async fn get_block(&self, block_number: U64) -> Result<Block<TxHash>>;
+
+    /// Get the block with the given block number, including full transactions.
+    async fn get_block_with_txs(&self, block_number: U64) -> Result<Block<Transaction>>;
+
+    /// Get the transaction with the given hash.
+    async fn get_transaction(&self, hash: TxHash) -> Result<Transaction>;
+
+    /// Get the receipt of the transaction with the given hash.
+    async fn get_transaction_receipt(&self, hash: TxHash) -> Result<TransactionReceipt>;
+
+    /// Get the receipts of all the transactions of the given block, in transaction order.
+    async fn get_block_receipts(&self, block_number: U64) -> Result<Vec<TransactionReceipt>>;
+
+    /// Get the pre-state of all accounts touched by the given transaction.
+    async fn get_prestate_trace(&self, hash: TxHash) -> Result<BTreeMap<Address, AccountState>>;
+
+    /// Get the pre-state of all accounts touched by each transaction of the given block, in transaction order.
+    async fn get_block_prestate_traces(
+        &self,
+        block_number: U64,
+    ) -> Result<Vec<BTreeMap<Address, AccountState>>>;
+
+    /// Get the state changes made by each transaction of the given block, in transaction order.
+    async fn get_block_state_diffs(&self, block_number: U64) -> Result<Vec<DiffMode>>;
+
+    /// Get the proof for an account + storage locations at a given block number.
+    async fn get_proof(
+        &self,
+        address: Address,
+        locations: Vec<H256>,
+        block_number: U64,
+    ) -> Result<EIP1186ProofResponse>;
+
+    /// Get the RLP encoding of the trie node with the given hash.
+    async fn get_node(&self, hash: H256) -> Result<Bytes>;
+}
+
+/// Tracing options for the debug_traceTransaction and debug_traceBlockByNumber calls.
+fn tracing_options() -> GethDebugTracingOptions {
+    GethDebugTracingOptions {
+        tracer: Some(GethDebugTracerType::BuiltInTracer(
+            GethDebugBuiltInTracerType::PreStateTracer,
+        )),
+
+        ..GethDebugTracingOptions::default()
+    }
+}
+
+/// Tracing options for the prestate tracer in diff mode, returning the state before and after each transaction.
+fn diff_tracing_options() -> GethDebugTracingOptions {
+    GethDebugTracingOptions {
+        tracer_config: Some(GethDebugTracerConfig::BuiltInTracer(
+            GethDebugBuiltInTracerConfig::PreStateTracer(PreStateConfig {
+                diff_mode: Some(true),
+            }),
+        )),
+        ..tracing_options()
+    }
+}
+
+/// Extract the prestate accounts from a trace returned by the prestate tracer.
+fn prestate_accounts(trace: GethTrace) -> Result<BTreeMap<Address, AccountState>> {
+    if let GethTrace::Known(GethTraceFrame::PreStateTracer(PreStateFrame::Default(accounts))) =
+        trace
+    {
+        Ok(accounts.0)
+    } else {
+        Err(anyhow!("Unexpected trace format: {:?}", trace))
+    }
+}
+
+/// Trace of a single transaction in a debug_traceBlockByNumber response.
+#[derive(Debug, Serialize, Deserialize)]
+struct BlockTraceResult<T> {
+    result: T,
+}
+
+#[async_trait]
+impl<P: JsonRpcClient> BlockDataSource for Provider<P> {
+    async fn get_chain_id(&self) -> Result<U256> {
+        Ok(self.get_chainid().await?)
+    }
+
+    async fn get_block(&self, block_number: U64) -> Result<Block<TxHash>> {
+        Middleware::get_block(self, block_number)
+            .await?
+            .ok_or_else(|| anyhow!("Block not found. Block number: {}", block_number))
+    }
+
+    async fn get_block_with_txs(&self, block_number: U64) -> Result<Block<Transaction>> {
+        Middleware::get_block_with_txs(self, block_number)
+            .await?
+            .ok_or_else(|| anyhow!("Block not found. Block number: {}", block_number))
+    }
+
+    async fn get_transaction(&self, hash: TxHash) -> Result<Transaction> {
+        Middleware::get_transaction(self, hash)
+            .await?
+            .ok_or_else(|| anyhow!("Transaction not found."))
+    }
+
+    async fn get_transaction_receipt(&self, hash: TxHash) -> Result<TransactionReceipt> {
+        Middleware::get_transaction_receipt(self, hash)
+            .await?
+            .ok_or_else(|| anyhow!("Receipt of transaction {:?} not found.", hash))
+    }
+
+    async fn get_block_receipts(&self, block_number: U64) -> Result<Vec<TransactionReceipt>> {
+        Ok(Middleware::get_block_receipts(self, block_number).await?)
+    }
+
+    async fn get_prestate_trace(&self, hash: TxHash) -> Result<BTreeMap<Address, AccountState>> {
+        let trace = self
+            .debug_trace_transaction(hash, tracing_options())
+            .await?;
+        prestate_accounts(trace)
+    }
+
+    async fn get_block_prestate_traces(
+        &self,
+        block_number: U64,
+    ) -> Result<Vec<BTreeMap<Address, AccountState>>> {
+        // Geth wraps each transaction trace in a `{"result": ...}` object, which `GethTrace` doesn't handle.
+        let traces: Vec<BlockTraceResult<BTreeMap<Address, AccountState>>> = self
+            .request(
+                "debug_traceBlockByNumber",
+                (BlockNumber::Number(block_number), tracing_options()),
+            )
+            .await?;
+        Ok(traces.into_iter().map(|t| t.result).collect())
+    }
+
+    async fn get_block_state_diffs(&self, block_number: U64) -> Result<Vec<DiffMode>> {
+        let traces: Vec<BlockTraceResult<DiffMode>> = self
+            .request(
+                "debug_traceBlockByNumber",
+                (BlockNumber::Number(block_number), diff_tracing_options()),
+            )
+            .await?;
+        Ok(traces.into_iter().map(|t| t.result).collect())
+    }
+
+    async fn get_proof(
+        &self,
+        address: Address,
+        locations: Vec<H256>,
+        block_number: U64,
+    ) -> Result<EIP1186ProofResponse> {
+        Ok(Middleware::get_proof(self, address, locations, Some(block_number.into())).await?)
+    }
+
+    async fn get_node(&self, hash: H256) -> Result<Bytes> {
+        // Only works on nodes storing trie nodes by hash, e.g. Geth with `--state.scheme=hash`.
+        Ok(self.request("debug_dbGet", [hash]).await?)
+    }
+}
+
+/// Data source routing each method family to a different backend:
+/// blocks and transactions to `blocks`, debug traces to `traces` and state proofs and trie nodes to `proofs`.
+pub struct RoutedSource<B, T, P> {
+    pub blocks: B,
+    pub traces: T,
+    pub proofs: P,
+}
+
+#[async_trait]
+impl<B: BlockDataSource, T: BlockDataSource, P: BlockDataSource> BlockDataSource
+    for RoutedSource<B, T, P>
+{
+    async fn get_chain_id(&self) -> Result<U256> {
+        self.blocks.get_chain_id().await
+    }
+
+    async fn get_block(&self, block_number: U64) -> Result<Block<TxHash>> {
+        self.blocks.get_block(block_number).await
+    }
+
+    async fn get_block_with_txs(&self, block_number: U64) -> Result<Block<Transaction>> {
+        self.blocks.get_block_with_txs(block_number).await
+    }
+
+    async fn get_transaction(&self, hash: TxHash) -> Result<Transaction> {
+        self.blocks.get_transaction(hash).await
+    }
+
+    async fn get_transaction_receipt(&self, hash: TxHash) -> Result<TransactionReceipt> {
+        self.blocks.get_transaction_receipt(hash).await
+    }
+
+    async fn get_block_receipts(&self, block_number: U64) -> Result<Vec<TransactionReceipt>> {
+        self.blocks.get_block_receipts(block_number).await
+    }
+
+    async fn get_prestate_trace(&self, hash: TxHash) -> Result<BTreeMap<Address, AccountState>> {
+        self.traces.get_prestate_trace(hash).await
+    }
+
+    async fn get_block_prestate_traces(
+        &self,
+        block_number: U64,
+    ) -> Result<Vec<BTreeMap<Address, AccountState>>> {
+        self.traces.get_block_prestate_traces(block_number).await
+    }
+
+    async fn get_block_state_diffs(&self, block_number: U64) -> Result<Vec<DiffMode>> {
+        self.traces.get_block_state_diffs(block_number).await
+    }
+
+    async fn get_proof(
+        &self,
+        address: Address,
+        locations: Vec<H256>,
+        block_number: U64,
+    ) -> Result<EIP1186ProofResponse> {
+        self.proofs
+            .get_proof(address, locations, block_number)
+            .await
+    }
+
+    async fn get_node(&self, hash: H256) -> Result<Bytes> {
+        self.proofs.get_node(hash).await
+    }
+}
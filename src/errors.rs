@@ -0,0 +1,190 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+use ethers::prelude::*;
+use regex::Regex;
+use thiserror::Error;
+
+static PC_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"KernelPanic in kernel at pc=([\w]+)").unwrap());
+static STACK_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"stack=\[([^\]]*)\]").unwrap());
+static SLOT_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"last_storage_slot=Some\(\((0x[0-9a-fA-F]+), (\d+), (\d+)\)\)").unwrap()
+});
+
+/// The trie in which a node is missing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TrieKind {
+    State,
+    Storage,
+}
+
+/// A trie node that witness generation needed but that is not in the partial tries,
+/// typically the sibling of a deleted leaf when a branch node collapses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MissingTrieNode {
+    pub trie: TrieKind,
+    /// Account whose trie is missing the node. For the state trie, the account that was deleted.
+    pub address: Address,
+    /// Storage slot whose deletion collapsed the branch. Zero for the state trie.
+    pub slot: U256,
+    /// Nibble of the missing child in the collapsed branch node.
+    pub nibble: u8,
+    /// Number of nibbles of the key below the collapsed branch node, i.e., 64 minus the depth of the branch node, as
+    /// reported by Plonky2.
+    pub depth: u8,
+}
+
+impl fmt::Display for MissingTrieNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "missing {:?} trie node: address: {:?}, slot: {}, nibble: {}, depth: {}",
+            self.trie, self.address, self.slot, self.nibble, self.depth
+        )
+    }
+}
+
+/// Failure of Plonky2 witness generation.
+#[derive(Debug, Error)]
+pub enum WitnessError {
+    #[error("{0}")]
+    MissingTrieNode(MissingTrieNode),
+    #[error("kernel panic at pc={pc}, stack={stack:?}")]
+    KernelPanic { pc: String, stack: Vec<U256> },
+    #[error("witness generation failed: {0:?}")]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for WitnessError {
+    /// Classify an error returned by witness generation.
+    /// The `prove_historical_blocks` branch of Plonky2 has no typed error for kernel panics: they are `anyhow!` errors
+    /// formatted as
+    /// `KernelPanic in kernel at pc=<label>, stack=[..], memory=[..], last_storage_slot=Some((<address>, <slot>, <depth>))`,
+    /// so each field is parsed from the message. The tests below pin this format, and must be updated with it.
+    fn from(e: anyhow::Error) -> Self {
+        let s = format!("{:?}", e);
+        let Some(pc) = PC_RE.captures(&s).map(|c| c[1].to_string()) else {
+            return WitnessError::Other(e);
+        };
+        let stack = STACK_RE
+            .captures(&s)
+            .map(|c| {
+                c[1].split(',')
+                    .filter_map(|x| U256::from_dec_str(x.trim()).ok())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if pc == "delete_hash_node_branch" {
+            let node = SLOT_RE.captures(&s).and_then(|c| {
+                Some(MissingTrieNode {
+                    trie: TrieKind::Storage,
+                    address: Address::from_str(&c[1]).ok()?,
+                    slot: U256::from_dec_str(&c[2]).ok()?,
+                    nibble: (*stack.first()?).try_into().ok()?,
+                    depth: c[3].parse().ok()?,
+                })
+            });
+            if let Some(node) = node {
+                return WitnessError::MissingTrieNode(node);
+            }
+        }
+        WitnessError::KernelPanic { pc, stack }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    /// Kernel panic as formatted by `simulate_cpu` in the `prove_historical_blocks` branch of Plonky2.
+    fn kernel_panic(
+        pc: &str,
+        stack: Vec<U256>,
+        memory: Vec<U256>,
+        last_storage_slot: Option<(Address, U256, usize)>,
+    ) -> anyhow::Error {
+        anyhow!(
+            "KernelPanic in kernel at pc={}, stack={:?}, memory={:?}, last_storage_slot={:?}",
+            pc,
+            stack,
+            memory,
+            last_storage_slot
+        )
+    }
+
+    fn deposit_contract() -> Address {
+        Address::from_str("0x00000000219ab540356cbb839cbe05303d7705fa").unwrap()
+    }
+
+    #[test]
+    fn parse_missing_storage_node() {
+        let e = kernel_panic(
+            "delete_hash_node_branch",
+            vec![7.into(), 1234.into(), 56.into()],
+            vec![0.into(), 1.into(), 2.into()],
+            Some((deposit_contract(), 42.into(), 61)),
+        );
+        assert_eq!(
+            e.to_string(),
+            "KernelPanic in kernel at pc=delete_hash_node_branch, stack=[7, 1234, 56], memory=[0, 1, 2], last_storage_slot=Some((0x00000000219ab540356cbb839cbe05303d7705fa, 42, 61))"
+        );
+        let WitnessError::MissingTrieNode(node) = e.into() else {
+            panic!("Expected a missing trie node");
+        };
+        assert_eq!(
+            node,
+            MissingTrieNode {
+                trie: TrieKind::Storage,
+                address: deposit_contract(),
+                slot: 42.into(),
+                nibble: 7,
+                depth: 61,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_kernel_panic() {
+        let e = kernel_panic(
+            "delete_hash_node_branch",
+            vec![3.into(), 4.into()],
+            vec![],
+            None,
+        );
+        assert_eq!(
+            e.to_string(),
+            "KernelPanic in kernel at pc=delete_hash_node_branch, stack=[3, 4], memory=[], last_storage_slot=None"
+        );
+        let WitnessError::KernelPanic { pc, stack } = e.into() else {
+            panic!("Expected a kernel panic");
+        };
+        assert_eq!(pc, "delete_hash_node_branch");
+        assert_eq!(stack, vec![3.into(), 4.into()]);
+    }
+
+    #[test]
+    fn parse_kernel_panic_with_context() {
+        let e = kernel_panic(
+            "mpt_insert_hash_node",
+            vec![U256::MAX],
+            vec![],
+            Some((deposit_contract(), 1.into(), 3)),
+        )
+        .context("Witness generation failed");
+        let WitnessError::KernelPanic { pc, stack } = e.into() else {
+            panic!("Expected a kernel panic");
+        };
+        assert_eq!(pc, "mpt_insert_hash_node");
+        assert_eq!(stack, vec![U256::MAX]);
+    }
+
+    #[test]
+    fn other_errors_are_not_parsed() {
+        let e = anyhow!("Gas limit exceeded");
+        assert!(matches!(e.into(), WitnessError::Other(_)));
+    }
+}
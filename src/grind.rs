@@ -0,0 +1,288 @@
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use eth_trie_utils::nibbles::Nibbles;
+use ethers::prelude::*;
+use ethers::utils::keccak256;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use thiserror::Error;
+
+use crate::utils::write_json_file;
+
+/// Number of candidates tried with the same RNG. Candidates are drawn chunk by chunk, each chunk with an RNG
+/// seeded from the grinding seed and the chunk index, so that the result doesn't depend on the number of threads.
+const CHUNK_SIZE: u64 = 1 << 12;
+
+/// Options for grinding a preimage whose Keccak hash has a given prefix.
+#[derive(Clone, Debug)]
+pub struct GrindOptions {
+    /// Seed of the RNG. Runs with the same seed find the same preimages.
+    pub seed: u64,
+    /// Maximum number of candidates to try.
+    pub max_attempts: u64,
+    /// Maximum time spent grinding a preimage.
+    pub timeout: Duration,
+    /// Number of threads grinding in parallel.
+    pub threads: usize,
+    /// Directory where found preimages are stored, so that later runs don't grind them again. `None` disables it.
+    pub preimage_dir: Option<PathBuf>,
+}
+
+impl Default for GrindOptions {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            max_attempts: 1 << 36,
+            timeout: Duration::from_secs(600),
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            preimage_dir: None,
+        }
+    }
+}
+
+/// Error returned when no preimage is found within the limits of the grinding options.
+#[derive(Debug, Error)]
+pub enum GrindError {
+    #[error("no preimage with hash prefix {prefix} found in {attempts} attempts")]
+    TooManyAttempts { prefix: String, attempts: u64 },
+    #[error("no preimage with hash prefix {prefix} found in {timeout:?}")]
+    Timeout { prefix: String, timeout: Duration },
+}
+
+/// Brute-force a preimage of `N` bytes such that its Keccak hash has the given nibbles as a prefix.
+/// Preimages found by previous runs are read from the preimage directory.
+pub fn grind<const N: usize>(
+    prefix: Nibbles,
+    options: &GrindOptions,
+) -> Result<[u8; N], GrindError> {
+    let target = nibbles(prefix);
+    let target_hex = target
+        .iter()
+        .map(|n| format!("{:x}", n))
+        .collect::<String>();
+    let path = options
+        .preimage_dir
+        .as_ref()
+        .map(|dir| dir.join(format!("preimage-{}-{}.json", N, target_hex)));
+    if let Some(preimage) = path.as_ref().and_then(|path| load_preimage::<N>(path)) {
+        if has_prefix(&keccak256(preimage), &target) {
+            return Ok(preimage);
+        }
+    }
+
+    let preimage = grind_parallel::<N>(&target, options).map_err(|e| match e {
+        Limit::Attempts => GrindError::TooManyAttempts {
+            prefix: target_hex.clone(),
+            attempts: options.max_attempts,
+        },
+        Limit::Timeout => GrindError::Timeout {
+            prefix: target_hex.clone(),
+            timeout: options.timeout,
+        },
+    })?;
+
+    if let Some(path) = path {
+        if let Err(e) = store_preimage(&path, &preimage) {
+            println!("Can't store the preimage in {:?}: {}", path, e);
+        }
+    }
+    Ok(preimage)
+}
+
+/// Limit reached without finding a preimage.
+enum Limit {
+    Attempts,
+    Timeout,
+}
+
+/// Try the candidates chunk by chunk on all threads, and return the first matching one in candidate order.
+/// If a chunk before the first match is skipped because of the timeout, the timeout is reported instead.
+fn grind_parallel<const N: usize>(target: &[u8], options: &GrindOptions) -> Result<[u8; N], Limit> {
+    let deadline = Instant::now() + options.timeout;
+    let max_chunks = options.max_attempts.div_ceil(CHUNK_SIZE);
+    let next_chunk = AtomicU64::new(0);
+    // Index and value of the first matching candidate found so far.
+    let best = Mutex::new(None::<(u64, [u8; N])>);
+    // First chunk skipped because of the timeout.
+    let first_skipped = AtomicU64::new(u64::MAX);
+
+    thread::scope(|s| {
+        for _ in 0..options.threads.max(1) {
+            s.spawn(|| loop {
+                let chunk = next_chunk.fetch_add(1, Ordering::Relaxed);
+                if chunk >= max_chunks {
+                    return;
+                }
+                // Chunks after a match can be skipped, but all chunks before it must be tried to find the first match.
+                if matches!(*best.lock().unwrap(), Some((index, _)) if index < chunk * CHUNK_SIZE) {
+                    return;
+                }
+                if Instant::now() > deadline {
+                    first_skipped.fetch_min(chunk, Ordering::Relaxed);
+                    return;
+                }
+                let mut rng = chunk_rng(options.seed, chunk);
+                let mut candidate = [0; N];
+                for i in 0..CHUNK_SIZE.min(options.max_attempts - chunk * CHUNK_SIZE) {
+                    rng.fill(&mut candidate[..]);
+                    if has_prefix(&keccak256(candidate), target) {
+                        let index = chunk * CHUNK_SIZE + i;
+                        let mut best = best.lock().unwrap();
+                        if best.is_none_or(|(b, _)| index < b) {
+                            *best = Some((index, candidate));
+                        }
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    let first_skipped = first_skipped.into_inner();
+    match best.into_inner().unwrap() {
+        Some((index, preimage)) if index / CHUNK_SIZE < first_skipped => Ok(preimage),
+        _ if first_skipped != u64::MAX => Err(Limit::Timeout),
+        _ => Err(Limit::Attempts),
+    }
+}
+
+/// RNG drawing the candidates of the given chunk.
+fn chunk_rng(seed: u64, chunk: u64) -> StdRng {
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&seed.to_be_bytes());
+    bytes[8..].copy_from_slice(&chunk.to_be_bytes());
+    StdRng::from_seed(keccak256(bytes))
+}
+
+fn nibbles(mut prefix: Nibbles) -> Vec<u8> {
+    let mut nibbles = vec![];
+    while prefix.count > 0 {
+        nibbles.push(prefix.pop_next_nibble_front());
+    }
+    nibbles
+}
+
+fn has_prefix(hash: &[u8; 32], target: &[u8]) -> bool {
+    target.iter().enumerate().all(|(i, &nibble)| {
+        let byte = hash[i / 2];
+        let actual = if i % 2 == 0 { byte >> 4 } else { byte & 0xf };
+        actual == nibble
+    })
+}
+
+fn load_preimage<const N: usize>(path: &Path) -> Option<[u8; N]> {
+    let file = File::open(path).ok()?;
+    let preimage: Bytes = serde_json::from_reader(BufReader::new(file)).ok()?;
+    preimage.as_ref().try_into().ok()
+}
+
+fn store_preimage(path: &Path, preimage: &[u8]) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    write_json_file(path, &Bytes::from(preimage.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix(nibbles: &[u8]) -> Nibbles {
+        let mut prefix = Nibbles {
+            count: 0,
+            packed: U256::zero(),
+        };
+        for &n in nibbles {
+            prefix.push_nibble_back(n);
+        }
+        prefix
+    }
+
+    fn options(threads: usize) -> GrindOptions {
+        GrindOptions {
+            threads,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn grind_is_deterministic() {
+        let target = prefix(&[0xa, 0xb, 0xc]);
+        let preimage = grind::<32>(target, &options(1)).unwrap();
+        assert!(has_prefix(&keccak256(preimage), &[0xa, 0xb, 0xc]));
+        assert_eq!(grind::<32>(target, &options(4)).unwrap(), preimage);
+    }
+
+    #[test]
+    fn grind_gives_up() {
+        let options = GrindOptions {
+            max_attempts: 1 << 10,
+            ..options(2)
+        };
+        let target = prefix(&[0; 16]);
+        assert!(matches!(
+            grind::<20>(target, &options),
+            Err(GrindError::TooManyAttempts { .. })
+        ));
+    }
+
+    #[test]
+    fn grind_honors_max_attempts() {
+        let target = [0xa];
+        let mut rng = chunk_rng(0, 0);
+        let mut candidate = [0; 20];
+        let first_match = (0..CHUNK_SIZE)
+            .find(|_| {
+                rng.fill(&mut candidate[..]);
+                has_prefix(&keccak256(candidate), &target)
+            })
+            .unwrap();
+        let limited = |max_attempts| GrindOptions {
+            max_attempts,
+            ..options(2)
+        };
+        assert!(matches!(
+            grind::<20>(prefix(&target), &limited(first_match)),
+            Err(GrindError::TooManyAttempts { .. })
+        ));
+        assert_eq!(
+            grind::<20>(prefix(&target), &limited(first_match + 1)).unwrap(),
+            candidate
+        );
+    }
+
+    #[test]
+    fn grind_times_out() {
+        let options = GrindOptions {
+            timeout: Duration::ZERO,
+            ..options(2)
+        };
+        assert!(matches!(
+            grind::<20>(prefix(&[0xa]), &options),
+            Err(GrindError::Timeout { .. })
+        ));
+    }
+
+    #[test]
+    fn grind_reuses_stored_preimages() {
+        let dir = std::env::temp_dir().join(format!("grind-test-{}", std::process::id()));
+        let options = GrindOptions {
+            preimage_dir: Some(dir.clone()),
+            ..options(2)
+        };
+        let target = prefix(&[1, 2, 3]);
+        let preimage = grind::<20>(target, &options).unwrap();
+        let no_attempts = GrindOptions {
+            max_attempts: 0,
+            ..options
+        };
+        assert_eq!(grind::<20>(target, &no_attempts).unwrap(), preimage);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
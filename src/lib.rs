@@ -1,28 +1,44 @@
-mod partial_tries;
+pub mod aggregation;
+pub mod bundle;
+pub mod cache;
+pub mod chain;
+pub mod data_source;
+pub mod errors;
+pub mod grind;
+pub mod partial_tries;
+pub mod preflight;
+pub mod proof_file;
+pub mod retry;
+pub mod state_diff;
+pub mod txns;
 pub mod utils;
 
-use ::core::panic;
-use rand::{thread_rng, Rng};
-use regex::Regex;
-use std::collections::{BTreeMap, HashMap, HashSet};
-use std::str::FromStr;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
-use crate::partial_tries::insert_proof;
-use anyhow::{anyhow, Result};
+use crate::chain::ChainConfig;
+use crate::data_source::BlockDataSource;
+use crate::errors::{MissingTrieNode, TrieKind, WitnessError};
+use crate::grind::{grind, GrindOptions};
+use crate::partial_tries::{
+    hash_node_at, insert_node, insert_proof, verify_proof, EMPTY_TRIE_HASH,
+};
+use crate::state_diff::{cleared_slots, collapsing_siblings, deleted_accounts};
+use crate::txns::get_block_receipts;
+use anyhow::{anyhow, ensure, Context, Result};
 use eth_trie_utils::nibbles::Nibbles;
 use eth_trie_utils::partial_trie::{HashedPartialTrie, Node, PartialTrie};
 use ethers::prelude::*;
-use ethers::types::GethDebugTracerType;
 use ethers::utils::keccak256;
-use ethers::utils::rlp;
+use ethers::utils::rlp::{self, Rlp};
+use futures::stream::{self, StreamExt, TryStreamExt};
 use plonky2::field::goldilocks_field::GoldilocksField;
-use plonky2::plonk::config::KeccakGoldilocksConfig;
+use plonky2::plonk::config::{KeccakGoldilocksConfig, PoseidonGoldilocksConfig};
 use plonky2::util::timing::TimingTree;
 use plonky2_evm::all_stark::AllStark;
 use plonky2_evm::config::StarkConfig;
 use plonky2_evm::generation::{GenerationInputs, TrieInputs};
-use plonky2_evm::proof::BlockMetadata;
-use plonky2_evm::prover::dont_prove_with_outputs;
+use plonky2_evm::proof::{AllProof, BlockMetadata, PublicValues};
+use plonky2_evm::prover::{dont_prove_with_outputs, prove};
 
 /// Keccak of empty bytes.
 const EMPTY_HASH: H256 = H256([
@@ -30,34 +46,129 @@ const EMPTY_HASH: H256 = H256([
     130, 39, 59, 123, 250, 216, 4, 93, 133, 164, 112,
 ]);
 
-/// Get the proof for an account + storage locations at a given block number.
-pub async fn get_proof(
+/// STARK proof of a block, along with its public values.
+pub type BlockProof = AllProof<GoldilocksField, PoseidonGoldilocksConfig, 2>;
+
+/// Options controlling how the witness of a block is fetched and proven.
+#[derive(Clone, Debug)]
+pub struct ProverOptions {
+    /// Fetch the block with full transactions and trace it with a single `debug_traceBlockByNumber` call,
+    /// instead of one `eth_getTransactionByHash` and `debug_traceTransaction` call per transaction.
+    /// Falls back to per-transaction tracing if the node rejects the block-level call.
+    pub trace_block: bool,
+    /// Maximum number of `eth_getProof` requests in flight at the same time.
+    pub max_concurrent_proofs: usize,
+    /// Configuration of the chain. If `None`, it is derived from the chain id returned by the node.
+    pub chain: Option<ChainConfig>,
+    /// Trace the state diff of the block to find the trie nodes needed for storage deletions before running
+    /// witness generation, instead of discovering them one witness generation failure at a time.
+    pub predict_missing_nodes: bool,
+    /// Options for grinding the keys whose proofs contain missing trie nodes that can't be fetched by hash.
+    pub grind: GrindOptions,
+    /// Maximum number of candidates tried when grinding a predicted trie node before witness generation. Predicted
+    /// nodes not found within this budget are left to witness generation.
+    pub prefetch_grind_attempts: u64,
+    /// Run the full STARK prover once witness generation succeeds, instead of only checking the state root after
+    /// witness generation.
+    pub full_proof: bool,
+    /// Prove the block one transaction at a time, starting each transaction from the state left by the previous one.
+    pub per_txn: bool,
+}
+
+impl Default for ProverOptions {
+    fn default() -> Self {
+        Self {
+            trace_block: true,
+            max_concurrent_proofs: 16,
+            chain: None,
+            predict_missing_nodes: true,
+            grind: GrindOptions::default(),
+            prefetch_grind_attempts: 1 << 24,
+            full_proof: false,
+            per_txn: false,
+        }
+    }
+}
+
+/// Get the configuration of the chain, either from the options or from the chain id returned by the node.
+pub async fn get_chain_config<D: BlockDataSource>(
+    source: &D,
+    options: &ProverOptions,
+) -> Result<ChainConfig> {
+    match &options.chain {
+        Some(chain) => Ok(chain.clone()),
+        None => Ok(ChainConfig::from_chain_id(source.get_chain_id().await?)),
+    }
+}
+
+/// Get the proof for an account + storage locations at a given block number,
+/// and verify it against the state root of that block.
+/// Also return whether the account is in the state trie, i.e., whether the account proof is an inclusion proof.
+pub async fn get_proof<D: BlockDataSource>(
     address: Address,
     locations: Vec<H256>,
     block_number: U64,
-    provider: &Provider<Http>,
+    state_root: H256,
+    source: &D,
 ) -> Result<(Vec<Bytes>, Vec<StorageProof>, H256, bool)> {
-    let proof = provider.get_proof(address, locations, Some(block_number.into()));
-    let proof = proof.await?;
-    let is_empty =
-        proof.balance.is_zero() && proof.nonce.is_zero() && proof.code_hash == EMPTY_HASH;
+    let proof = source.get_proof(address, locations, block_number).await?;
+    let exists = verify_account_proof(state_root, &proof)?;
     Ok((
         proof.account_proof,
         proof.storage_proof,
         proof.storage_hash,
-        is_empty,
+        exists,
     ))
 }
 
-/// Tracing options for the debug_traceTransaction call.
-fn tracing_options() -> GethDebugTracingOptions {
-    GethDebugTracingOptions {
-        tracer: Some(GethDebugTracerType::BuiltInTracer(
-            GethDebugBuiltInTracerType::PreStateTracer,
-        )),
-
-        ..GethDebugTracingOptions::default()
+/// Verify the account and storage proofs of an `eth_getProof` response against the given state root,
+/// and check that the proven values match the ones in the response.
+/// Return whether the account is in the state trie.
+fn verify_account_proof(state_root: H256, proof: &EIP1186ProofResponse) -> Result<bool> {
+    let address = proof.address;
+    let account = verify_proof(state_root, keccak256(address.0), &proof.account_proof)
+        .with_context(|| format!("Invalid proof for account {:?}", address))?;
+    let exists = account.is_some();
+    let storage_root = match account {
+        Some(account) => {
+            let rlp = Rlp::new(&account);
+            let nonce: U256 = rlp.val_at(0)?;
+            let balance: U256 = rlp.val_at(1)?;
+            let storage_root: H256 = rlp.val_at(2)?;
+            let code_hash: H256 = rlp.val_at(3)?;
+            ensure!(
+                nonce == proof.nonce.as_u64().into()
+                    && balance == proof.balance
+                    && storage_root == proof.storage_hash
+                    && code_hash == proof.code_hash,
+                "Proven account {:?} doesn't match the eth_getProof response",
+                address
+            );
+            storage_root
+        }
+        None => EMPTY_TRIE_HASH,
+    };
+    for sp in &proof.storage_proof {
+        let value = verify_proof(storage_root, keccak256(sp.key.0), &sp.proof)
+            .with_context(|| {
+                format!(
+                    "Invalid storage proof for account {:?}, slot {:?}",
+                    address, sp.key
+                )
+            })?
+            .map(|v| rlp::decode::<U256>(&v))
+            .transpose()?
+            .unwrap_or_default();
+        ensure!(
+            value == sp.value,
+            "Proven value {} of slot {:?} of account {:?} doesn't match the value {} in the eth_getProof response",
+            value,
+            sp.key,
+            address,
+            sp.value
+        );
     }
+    Ok(exists)
 }
 
 /// Hash map from code hash to code.
@@ -68,124 +179,563 @@ fn contract_codes() -> HashMap<H256, Vec<u8>> {
     map
 }
 
-/// Get the Plonky2 block metadata at the given block number.
-pub async fn get_block_metadata(
-    block_number: U64,
-    block_chain_id: U256,
-    provider: &Provider<Http>,
-) -> Result<(BlockMetadata, H256)> {
-    let block = provider
-        .get_block(block_number)
-        .await?
-        .ok_or_else(|| anyhow!("Block not found. Block number: {}", block_number))?;
-    Ok((
-        BlockMetadata {
-            block_beneficiary: block.author.unwrap(),
-            block_timestamp: block.timestamp,
-            block_number: U256([block_number.0[0], 0, 0, 0]),
-            block_difficulty: block.difficulty,
-            block_gaslimit: block.gas_limit,
-            block_chain_id,
-            block_base_fee: block.base_fee_per_gas.unwrap(),
-        },
-        block.state_root,
-    ))
+/// Get the Plonky2 block metadata of the given block.
+/// Fails if the node omits the beneficiary, number or base fee of the block, e.g. for pre-London blocks.
+pub fn get_block_metadata(block: &Block<TxHash>, block_chain_id: U256) -> Result<BlockMetadata> {
+    let block_number = block.number.context("Block has no number")?;
+    Ok(BlockMetadata {
+        block_beneficiary: block
+            .author
+            .with_context(|| format!("Block {} has no beneficiary", block_number))?,
+        block_timestamp: block.timestamp,
+        block_number: U256([block_number.0[0], 0, 0, 0]),
+        block_difficulty: block.difficulty,
+        block_gaslimit: block.gas_limit,
+        block_chain_id,
+        block_base_fee: block
+            .base_fee_per_gas
+            .with_context(|| format!("Block {} has no base fee", block_number))?,
+    })
+}
+
+/// Build the transactions and receipts tries of a block, keyed by the RLP encoding of the transaction index, and check
+/// their roots against the block header.
+fn get_txn_and_receipt_tries(
+    block: &Block<TxHash>,
+    txn_rlps: &[Vec<u8>],
+    receipts: &[TransactionReceipt],
+) -> Result<(HashedPartialTrie, HashedPartialTrie)> {
+    ensure!(
+        receipts.len() == txn_rlps.len(),
+        "Block has {} transactions, but {} receipts",
+        txn_rlps.len(),
+        receipts.len()
+    );
+    let mut transactions_trie = HashedPartialTrie::new(Node::Empty);
+    let mut receipts_trie = HashedPartialTrie::new(Node::Empty);
+    for (i, (txn_rlp, receipt)) in txn_rlps.iter().zip(receipts).enumerate() {
+        let key = Nibbles::from_bytes_be(&rlp::encode(&i))?;
+        transactions_trie.insert(key, txn_rlp.clone());
+        receipts_trie.insert(key, receipt_rlp(receipt));
+    }
+    ensure!(
+        transactions_trie.hash() == block.transactions_root,
+        "Transactions trie has root {:?}, expected {:?}",
+        transactions_trie.hash(),
+        block.transactions_root
+    );
+    ensure!(
+        receipts_trie.hash() == block.receipts_root,
+        "Receipts trie has root {:?}, expected {:?}",
+        receipts_trie.hash(),
+        block.receipts_root
+    );
+    Ok((transactions_trie, receipts_trie))
+}
+
+/// Encoding of a receipt in the receipts trie: its RLP encoding, prefixed with the transaction type for typed
+/// transactions, as in EIP-2718.
+fn receipt_rlp(receipt: &TransactionReceipt) -> Vec<u8> {
+    let rlp = rlp::encode(receipt).to_vec();
+    match receipt.transaction_type {
+        Some(ty) if !ty.is_zero() => [vec![ty.as_u64() as u8], rlp].concat(),
+        _ => rlp,
+    }
+}
+
+/// Get the withdrawals of a block, and its transactions along with their prestate traces.
+#[allow(clippy::type_complexity)]
+async fn get_block_txns<D: BlockDataSource>(
+    block_number: u64,
+    source: &D,
+    options: &ProverOptions,
+) -> Result<(
+    Option<Vec<Withdrawal>>,
+    Vec<(Transaction, BTreeMap<Address, AccountState>)>,
+)> {
+    let (withdrawals, txns) = if options.trace_block {
+        let block = source.get_block_with_txs(block_number.into()).await?;
+        (block.withdrawals, block.transactions)
+    } else {
+        let block = source.get_block(block_number.into()).await?;
+        let mut txns = vec![];
+        for hash in block.transactions {
+            txns.push(source.get_transaction(hash).await?);
+        }
+        (block.withdrawals, txns)
+    };
+
+    let block_traces = if options.trace_block {
+        match source.get_block_prestate_traces(block_number.into()).await {
+            Ok(traces) if traces.len() == txns.len() => Some(traces),
+            Ok(traces) => {
+                println!(
+                    "Block-level tracing returned {} traces for {} transactions, falling back to per-transaction tracing.",
+                    traces.len(),
+                    txns.len()
+                );
+                None
+            }
+            Err(e) => {
+                println!(
+                    "Block-level tracing failed, falling back to per-transaction tracing: {}",
+                    e
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let traces = match block_traces {
+        Some(traces) => traces,
+        None => {
+            let mut traces = vec![];
+            for txn in &txns {
+                traces.push(source.get_prestate_trace(txn.hash).await?);
+            }
+            traces
+        }
+    };
+
+    Ok((withdrawals, txns.into_iter().zip(traces).collect()))
 }
 
-/// Brute-force a H256 such that its Keccak hash has the given nibbles as a prefix.
-fn grind(nibs: Nibbles, depth: usize) -> Result<H256> {
-    let mut rng = thread_rng();
+/// Prove an Ethereum block given its block number.
+/// Witness generation can fail because of trie nodes missing from the partial tries. Each missing node is inserted in
+/// the partial trie containing it, and witness generation is retried. The rest of the witness is kept between attempts.
+/// Return the proof of the block if `options.full_proof` is set, once witness generation succeeds.
+pub async fn prove_block_loop<D: BlockDataSource>(
+    block_number: u64,
+    source: &D,
+    options: &ProverOptions,
+) -> Result<Option<BlockProof>> {
+    let (mut inputs, block, _) = get_block_inputs(block_number, source, options).await?;
+    let final_hash = block.state_root;
+    if options.predict_missing_nodes {
+        prefetch_missing_nodes(block_number, &mut inputs, source, options).await?;
+    }
     loop {
-        let bytes: [u8; 32] = rng.gen();
-        let h = keccak256(bytes);
-        let n = Nibbles::from_bytes_be(&h)?;
-        let n = n.truncate_n_nibbles_back(depth);
-        if n == nibs {
-            println!("{} {:?} {}", hex::encode(bytes), n, nibs);
-            return Ok(bytes.into());
+        let error = match prove_block_real_deal(inputs.clone(), final_hash) {
+            Ok(()) => break,
+            Err(e) => e,
+        };
+        let node = find_missing_node(block_number, &mut inputs, error, source, options).await?;
+        println!("Block number: {}, {}", block_number, node);
+        recover_missing_node(block_number, &mut inputs, &node, source, options).await?;
+    }
+    if !options.full_proof {
+        return Ok(None);
+    }
+    prove_block_full(inputs, final_hash).map(Some)
+}
+
+/// Find the trie node missing from the partial tries of the block that made witness generation fail.
+/// Other failures are returned as errors.
+async fn find_missing_node<D: BlockDataSource>(
+    block_number: u64,
+    inputs: &mut GenerationInputs,
+    error: WitnessError,
+    source: &D,
+    options: &ProverOptions,
+) -> Result<MissingTrieNode> {
+    match error {
+        WitnessError::MissingTrieNode(node) => {
+            if missing_node_hash(inputs, &node).is_ok() {
+                Ok(node)
+            } else {
+                // Plonky2 only reports the last storage slot it accessed, which is unrelated to the panic when
+                // it happens while deleting an account. The missing node is then in the state trie instead.
+                find_state_trie_node(block_number, inputs, node.nibble, source, options).await
+            }
         }
+        WitnessError::KernelPanic { pc, stack } if pc == "delete_hash_node_branch" => {
+            let nibble = stack
+                .first()
+                .and_then(|n| u8::try_from(*n).ok())
+                .with_context(|| format!("Invalid stack in kernel panic: {:?}", stack))?;
+            find_state_trie_node(block_number, inputs, nibble, source, options).await
+        }
+        e => Err(e.into()),
     }
 }
 
-/// Prove an Ethereum block given its block number.
-/// Proving a block can fail if not enough storage locations are known. This function repeatedly tries to
-/// prove the block until it succeeds by adding new storage locations at every iteration of the loop.
-pub async fn prove_block_loop(block_number: u64, provider: &Provider<Http>) -> Result<()> {
-    let mut slots = HashMap::new();
-    while let Some((nibble, address, slot, depth)) =
-        prove_block(block_number, &slots, provider).await?
-    {
+/// Key of a missing trie node: the hashed address for the state trie, the hashed storage slot otherwise.
+fn missing_node_key(node: &MissingTrieNode) -> [u8; 32] {
+    match node.trie {
+        TrieKind::State => keccak256(node.address.0),
+        TrieKind::Storage => {
+            let mut bytes = [0; 32];
+            node.slot.to_big_endian(&mut bytes);
+            keccak256(bytes)
+        }
+    }
+}
+
+/// Path of a missing trie node, i.e., the path of the collapsed branch node followed by the nibble of the missing child.
+fn missing_node_prefix(node: &MissingTrieNode) -> Result<Nibbles> {
+    let nibs = Nibbles::from_bytes_be(&missing_node_key(node))?;
+    let mut nibs = nibs.truncate_n_nibbles_back(node.depth as usize);
+    nibs.push_nibble_back(node.nibble);
+    Ok(nibs)
+}
+
+/// Partial trie containing a missing trie node.
+fn missing_node_trie<'a>(
+    tries: &'a mut TrieInputs,
+    node: &MissingTrieNode,
+) -> Result<&'a mut HashedPartialTrie> {
+    match node.trie {
+        TrieKind::State => Ok(&mut tries.state_trie),
+        TrieKind::Storage => {
+            let address_hash = H256(keccak256(node.address.0));
+            tries
+                .storage_tries
+                .iter_mut()
+                .find(|(a, _)| *a == address_hash)
+                .map(|(_, trie)| trie)
+                .with_context(|| format!("No storage trie for account {:?}", node.address))
+        }
+    }
+}
+
+/// Hash of a missing trie node, read from the hash node in its place in the partial trie.
+fn missing_node_hash(inputs: &mut GenerationInputs, node: &MissingTrieNode) -> Result<H256> {
+    let trie = missing_node_trie(&mut inputs.tries, node)?;
+    let prefix = missing_node_prefix(node)?;
+    hash_node_at(trie, prefix)
+        .with_context(|| format!("No hash node at {:?} in the partial trie", prefix))
+}
+
+/// Find the state trie node missing to delete an account, given the nibble of the missing child.
+/// Plonky2 doesn't report the deleted account nor the depth of the collapsing branch node, so the candidates are the
+/// nodes needed to delete the accounts deleted according to the state diff, or any of the accounts touched by the block
+/// if the node can't trace the state diff. Only the candidates with the given nibble that are still hash nodes in the
+/// partial trie are kept. If several are left, the first one is returned: if witness generation needed another one, it
+/// fails again once the first one is inserted, and the next one is returned.
+async fn find_state_trie_node<D: BlockDataSource>(
+    block_number: u64,
+    inputs: &mut GenerationInputs,
+    nibble: u8,
+    source: &D,
+    options: &ProverOptions,
+) -> Result<MissingTrieNode> {
+    let candidates = match source.get_block_state_diffs(block_number.into()).await {
+        Ok(diffs) => state_missing_nodes(inputs, &diffs)?,
+        Err(e) => {
+            println!(
+                "Can't trace the state diff of the block, any account touched by the block can be the deleted one: {:#}",
+                e
+            );
+            let (_, txns) = get_block_txns(block_number, source, options).await?;
+            let touched = txns
+                .into_iter()
+                .flat_map(|(_, accounts)| accounts.into_keys())
+                .collect::<BTreeSet<_>>();
+            account_missing_nodes(inputs, touched)?
+        }
+    };
+    let candidates = candidates
+        .into_iter()
+        .filter(|node| node.nibble == nibble && missing_node_hash(inputs, node).is_ok())
+        .collect::<Vec<_>>();
+    if candidates.len() > 1 {
         println!(
-            "Block number: {}, nibble: {}, address: {}, slot: {}, depth: {}",
-            block_number, nibble, address, slot, depth
+            "{} state trie nodes with nibble {} can be the missing one, trying the first one",
+            candidates.len(),
+            nibble
         );
-        let mut bytes = [0; 32];
-        slot.to_big_endian(&mut bytes);
-        let h = keccak256(bytes);
-        let nibs = Nibbles::from_bytes_be(&h)?;
-        let mut nibs = nibs.truncate_n_nibbles_back(depth as usize);
-        nibs.push_nibble_back(nibble);
-        let s = grind(nibs, depth as usize - 1)?;
-        slots.entry(address).or_insert_with(Vec::new).push(s);
+    }
+    candidates.into_iter().next().with_context(|| {
+        format!(
+            "Witness generation is missing a trie node, but no deleted account needs a node with nibble {}",
+            nibble
+        )
+    })
+}
+
+/// Trie nodes that witness generation would need to delete any single one of the given accounts.
+fn account_missing_nodes(
+    inputs: &GenerationInputs,
+    accounts: impl IntoIterator<Item = Address>,
+) -> Result<Vec<MissingTrieNode>> {
+    let mut nodes = vec![];
+    for address in accounts {
+        let key = Nibbles::from_bytes_be(&keccak256(address.0))?;
+        let deleted = HashMap::from([(key, (address, U256::zero()))]);
+        nodes.extend(missing_nodes(
+            TrieKind::State,
+            &inputs.tries.state_trie,
+            &deleted,
+        ));
+    }
+    Ok(nodes)
+}
+
+/// Trie nodes that witness generation will need to delete the accounts deleted by the block.
+fn state_missing_nodes(
+    inputs: &GenerationInputs,
+    diffs: &[DiffMode],
+) -> Result<Vec<MissingTrieNode>> {
+    let deleted = deleted_accounts(diffs)
+        .into_iter()
+        .map(|address| {
+            let key = Nibbles::from_bytes_be(&keccak256(address.0))?;
+            Ok((key, (address, U256::zero())))
+        })
+        .collect::<Result<HashMap<_, _>>>()?;
+    Ok(missing_nodes(
+        TrieKind::State,
+        &inputs.tries.state_trie,
+        &deleted,
+    ))
+}
+
+/// Trie nodes that witness generation will need to delete the storage slots cleared by the block.
+fn storage_missing_nodes(
+    inputs: &GenerationInputs,
+    diffs: &[DiffMode],
+) -> Result<Vec<MissingTrieNode>> {
+    let mut nodes = vec![];
+    for (address, cleared) in cleared_slots(diffs) {
+        let address_hash = H256(keccak256(address.0));
+        let Some((_, trie)) = inputs
+            .tries
+            .storage_tries
+            .iter()
+            .find(|(a, _)| *a == address_hash)
+        else {
+            continue;
+        };
+        let deleted = cleared
+            .into_iter()
+            .map(|slot| {
+                let key = Nibbles::from_bytes_be(&keccak256(slot.0))?;
+                Ok((key, (address, U256::from_big_endian(&slot.0))))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        nodes.extend(missing_nodes(TrieKind::Storage, trie, &deleted));
+    }
+    Ok(nodes)
+}
+
+/// Trie nodes needed to collapse the branch nodes of `trie` when the `deleted` keys are removed from it.
+/// Each deleted key is mapped to its account and storage slot.
+fn missing_nodes(
+    trie_kind: TrieKind,
+    trie: &HashedPartialTrie,
+    deleted: &HashMap<Nibbles, (Address, U256)>,
+) -> Vec<MissingTrieNode> {
+    let keys = deleted.keys().copied().collect();
+    collapsing_siblings(trie, &keys)
+        .into_iter()
+        .filter_map(|sibling| {
+            let depth = 64 - sibling.branch.count;
+            let (_, &(address, slot)) = deleted
+                .iter()
+                .find(|(key, _)| key.truncate_n_nibbles_back(depth) == sibling.branch)?;
+            Some(MissingTrieNode {
+                trie: trie_kind,
+                address,
+                slot,
+                nibble: sibling.nibble,
+                depth: depth as u8,
+            })
+        })
+        .collect()
+}
+
+/// Insert the trie nodes that witness generation will need to delete the accounts and storage slots removed by the block.
+/// The removed accounts and slots are known from the state diff of the block, so the branch nodes collapsing when they
+/// are deleted can be found in the partial tries, and their remaining child inserted before running witness generation.
+/// Nodes that can't be fetched by hash are ground within `options.prefetch_grind_attempts`, as some of them can end up
+/// not being needed. Nodes that can't be recovered are left to witness generation.
+async fn prefetch_missing_nodes<D: BlockDataSource>(
+    block_number: u64,
+    inputs: &mut GenerationInputs,
+    source: &D,
+    options: &ProverOptions,
+) -> Result<()> {
+    let diffs = match source.get_block_state_diffs(block_number.into()).await {
+        Ok(diffs) => diffs,
+        Err(e) => {
+            println!(
+                "Can't trace the state diff of the block, missing trie nodes will be found during witness generation: {}",
+                e
+            );
+            return Ok(());
+        }
+    };
+    let mut nodes = state_missing_nodes(inputs, &diffs)?;
+    nodes.extend(storage_missing_nodes(inputs, &diffs)?);
+    let options = ProverOptions {
+        grind: GrindOptions {
+            max_attempts: options.prefetch_grind_attempts,
+            ..options.grind.clone()
+        },
+        ..options.clone()
+    };
+    for node in nodes {
+        // Several predicted nodes can be recovered by the same proof.
+        if missing_node_hash(inputs, &node).is_err() {
+            continue;
+        }
+        if let Err(e) = recover_missing_node(block_number, inputs, &node, source, &options).await {
+            println!(
+                "Can't recover the {}, leaving it to witness generation: {:#}",
+                node, e
+            );
+        }
     }
     Ok(())
 }
 
-/// Prove an Ethereum block given its block number and some extra storage slots.
-async fn prove_block(
+/// Insert a missing trie node in the partial trie containing it. The node is fetched by hash if possible.
+/// Otherwise, a key whose proof goes through the node is found by grinding, and its proof is inserted instead.
+async fn recover_missing_node<D: BlockDataSource>(
     block_number: u64,
-    slots: &HashMap<Address, Vec<H256>>,
-    provider: &Provider<Http>,
-) -> Result<Option<(u8, Address, U256, u8)>> {
-    let block = provider
-        .get_block(block_number)
-        .await?
-        .ok_or_else(|| anyhow!("Block not found. Block number: {}", block_number))?;
+    inputs: &mut GenerationInputs,
+    node: &MissingTrieNode,
+    source: &D,
+    options: &ProverOptions,
+) -> Result<()> {
+    match resolve_missing_node(inputs, node, source).await {
+        Ok(()) => {
+            println!("Fetched the {}", node);
+            return Ok(());
+        }
+        Err(e) => println!(
+            "Can't fetch the {}, falling back to grinding: {:#}",
+            node, e
+        ),
+    }
+    let (address, locations) = grind_missing_node(node, &options.grind).await?;
+    let state_root = inputs.tries.state_trie.hash();
+    let (proof, storage_proof, _, exists) = get_proof(
+        address,
+        locations,
+        (block_number - 1).into(),
+        state_root,
+        source,
+    )
+    .await?;
+    let trie = missing_node_trie(&mut inputs.tries, node)?;
+    match node.trie {
+        TrieKind::State => {
+            insert_proof(
+                trie,
+                keccak256(address.0),
+                proof,
+                exists,
+                &mut HashSet::new(),
+            )
+            .with_context(|| format!("Invalid proof for account {:?}", address))?;
+        }
+        TrieKind::Storage => {
+            for sp in storage_proof {
+                insert_proof(
+                    trie,
+                    keccak256(sp.key.0),
+                    sp.proof,
+                    !sp.value.is_zero(),
+                    &mut HashSet::new(),
+                )
+                .with_context(|| {
+                    format!(
+                        "Invalid storage proof for account {:?}, slot {:?}",
+                        address, sp.key
+                    )
+                })?;
+            }
+        }
+    }
+    ensure!(
+        missing_node_hash(inputs, node).is_err(),
+        "The proof of the ground key doesn't contain the {}",
+        node
+    );
+    Ok(())
+}
+
+/// Grind a key whose proof goes through a missing trie node: an address for the state trie, a storage slot otherwise.
+/// Return the account and the storage slots to prove. Grinding runs on a blocking thread, as it keeps all cores busy.
+async fn grind_missing_node(
+    node: &MissingTrieNode,
+    options: &GrindOptions,
+) -> Result<(Address, Vec<H256>)> {
+    let prefix = missing_node_prefix(node)?;
+    let (trie, address, options) = (node.trie, node.address, options.clone());
+    tokio::task::spawn_blocking(move || -> Result<(Address, Vec<H256>)> {
+        match trie {
+            TrieKind::State => Ok((Address::from(grind::<20>(prefix, &options)?), vec![])),
+            TrieKind::Storage => Ok((address, vec![H256(grind::<32>(prefix, &options)?)])),
+        }
+    })
+    .await?
+}
+
+/// Fetch a trie node by its hash, and check that it matches the hash.
+async fn fetch_node<D: BlockDataSource>(hash: H256, source: &D) -> Result<Bytes> {
+    let node = source.get_node(hash).await?;
+    let actual = H256(keccak256(&node));
+    ensure!(
+        actual == hash,
+        "Trie node returned for hash {:?} has hash {:?}",
+        hash,
+        actual
+    );
+    Ok(node)
+}
+
+/// Fetch a missing trie node by its hash, and insert it in place of its hash node in the partial trie.
+async fn resolve_missing_node<D: BlockDataSource>(
+    inputs: &mut GenerationInputs,
+    node: &MissingTrieNode,
+    source: &D,
+) -> Result<()> {
+    let hash = missing_node_hash(inputs, node)?;
+    let preimage = fetch_node(hash, source).await?;
+    let trie = missing_node_trie(&mut inputs.tries, node)?;
+    insert_node(trie, missing_node_prefix(node)?, &preimage, 0)?;
+    Ok(())
+}
+
+/// Build the Plonky2 generation inputs of a block given its block number.
+/// The transactions and receipts tries are built from the transactions and receipts of the block, and checked against
+/// the block header.
+/// Also return the block and the receipts of its transactions.
+pub async fn get_block_inputs<D: BlockDataSource>(
+    block_number: u64,
+    source: &D,
+    options: &ProverOptions,
+) -> Result<(GenerationInputs, Block<TxHash>, Vec<TransactionReceipt>)> {
+    let (block_withdrawals, txns) = get_block_txns(block_number, source, options).await?;
+    let prev_block = source.get_block((block_number - 1).into()).await?;
     let mut trie = HashedPartialTrie::new(Node::Empty);
     let mut dont_touch_these_nibbles = HashSet::new();
     let mut contract_codes = contract_codes();
     let mut storage_tries = vec![];
     let mut txn_rlps = vec![];
-    let chain_id = U256::one();
+    let chain = get_chain_config(source, options).await?;
     let mut alladdrs = vec![];
-    if let Some(withdrawals) = &block.withdrawals {
+    if let Some(withdrawals) = &block_withdrawals {
         for withdrawal in withdrawals {
             alladdrs.push(withdrawal.address);
-            let (proof, _, _, is_empty) = get_proof(
+            let (proof, _, _, exists) = get_proof(
                 withdrawal.address,
                 vec![],
                 (block_number - 1).into(),
-                provider,
+                prev_block.state_root,
+                source,
             )
             .await?;
             let key = keccak256(withdrawal.address.0);
-            insert_proof(
-                &mut trie,
-                key,
-                proof,
-                !is_empty, /* is this correct? */
-                &mut dont_touch_these_nibbles,
-            )?;
+            insert_proof(&mut trie, key, proof, exists, &mut dont_touch_these_nibbles)
+                .with_context(|| format!("Invalid proof for account {:?}", withdrawal.address))?;
         }
     }
     let mut all_accounts = BTreeMap::<Address, AccountState>::new();
-    for hash in block.transactions.into_iter() {
-        let txn = provider.get_transaction(hash);
-        let txn = txn
-            .await?
-            .ok_or_else(|| anyhow!("Transaction not found."))?;
-        // chain_id = txn.chain_id.unwrap(); // TODO: For type-0 txn, the chain_id is not set so the unwrap panics.
-        let trace = provider
-            .debug_trace_transaction(hash, tracing_options())
-            .await?;
-        let accounts = if let GethTrace::Known(GethTraceFrame::PreStateTracer(
-            PreStateFrame::Default(accounts),
-        )) = trace
-        {
-            accounts.0
-        } else {
-            panic!("wtf?");
-        };
+    for (txn, accounts) in txns {
         for (address, account) in accounts {
             alladdrs.push(address);
             if let Some(acc) = all_accounts.get(&address) {
@@ -210,29 +760,46 @@ async fn prove_block(
         txn_rlps.push(txn.rlp().to_vec());
     }
 
-    for (address, account) in all_accounts {
-        let AccountState { code, storage, .. } = account;
-        let empty_storage = storage.is_none();
-        let mut storage_keys = storage
-            .unwrap_or_default()
-            .keys()
-            .copied()
-            .collect::<Vec<_>>();
-        if let Some(v) = slots.get(&address) {
-            for slot in v {
-                storage_keys.push(*slot);
-            }
-        }
-        let (proof, storage_proof, storage_hash, account_is_empty) =
-            get_proof(address, storage_keys, (block_number - 1).into(), provider).await?;
+    let accounts = all_accounts
+        .into_iter()
+        .map(|(address, account)| {
+            let AccountState { code, storage, .. } = account;
+            let empty_storage = storage.is_none();
+            let storage_keys = storage
+                .unwrap_or_default()
+                .keys()
+                .copied()
+                .collect::<Vec<_>>();
+            (address, code, empty_storage, storage_keys)
+        })
+        .collect::<Vec<_>>();
+    // Fetch the proofs concurrently. `buffered` yields them in the same order as `accounts`,
+    // so they are inserted in the tries in the same order as if they were fetched sequentially.
+    let proofs = stream::iter(&accounts)
+        .map(|(address, _, _, storage_keys)| {
+            get_proof(
+                *address,
+                storage_keys.clone(),
+                (block_number - 1).into(),
+                prev_block.state_root,
+                source,
+            )
+        })
+        .buffered(options.max_concurrent_proofs.max(1))
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    for ((address, code, empty_storage, _), proof) in accounts.into_iter().zip(proofs) {
+        let (proof, storage_proof, storage_hash, account_exists) = proof;
         let key = keccak256(address.0);
         insert_proof(
             &mut trie,
             key,
             proof,
-            !account_is_empty,
+            account_exists,
             &mut dont_touch_these_nibbles,
-        )?;
+        )
+        .with_context(|| format!("Invalid proof for account {:?}", address))?;
         if !empty_storage {
             let mut storage_trie = HashedPartialTrie::new(Node::Empty);
             let mut dont_touch_these_nibbles_storage = HashSet::new();
@@ -243,17 +810,41 @@ async fn prove_block(
                     sp.proof,
                     !sp.value.is_zero(),
                     &mut dont_touch_these_nibbles_storage,
-                )?;
+                )
+                .with_context(|| {
+                    format!(
+                        "Invalid storage proof for account {:?}, slot {:?}",
+                        address, sp.key
+                    )
+                })?;
                 if !sp.value.is_zero() {
                     let x = rlp::decode::<U256>(
                         storage_trie
                             .get(Nibbles::from_bytes_be(&keccak256(sp.key.0))?)
-                            .unwrap(),
+                            .with_context(|| {
+                                format!(
+                                    "Storage trie of account {:?} has no value at slot {:?}",
+                                    address, sp.key
+                                )
+                            })?,
                     )?;
-                    assert_eq!(x, sp.value);
+                    ensure!(
+                        x == sp.value,
+                        "Storage trie of account {:?} has value {} at slot {:?}, expected {}",
+                        address,
+                        x,
+                        sp.key,
+                        sp.value
+                    );
                 }
             }
-            assert_eq!(storage_hash, storage_trie.hash());
+            ensure!(
+                storage_hash == storage_trie.hash(),
+                "Storage trie of account {:?} has root {:?}, expected {:?}",
+                address,
+                storage_trie.hash(),
+                storage_hash
+            );
             storage_tries.push((key.into(), storage_trie));
         }
         if let Some(code) = code {
@@ -263,182 +854,85 @@ async fn prove_block(
         }
     }
 
-    let prev_block = provider
-        .get_block(block_number - 1)
-        .await?
-        .ok_or_else(|| anyhow!("Block not found. Block number: {}", block_number - 1))?;
-    assert_eq!(prev_block.state_root, trie.hash());
+    ensure!(
+        prev_block.state_root == trie.hash(),
+        "State trie has root {:?}, expected {:?}",
+        trie.hash(),
+        prev_block.state_root
+    );
 
-    let (block_metadata, final_hash) =
-        get_block_metadata(block_number.into(), chain_id, provider).await?;
-    let withdrawals = if let Some(v) = block.withdrawals {
+    let block = source.get_block(block_number.into()).await?;
+    let block_metadata = get_block_metadata(&block, chain.chain_id)?;
+    let withdrawals = if let Some(v) = block_withdrawals {
         v.into_iter()
             .map(|w| (w.address, w.amount * 1_000_000_000)) // Alchemy returns Gweis for some reason
             .collect()
     } else {
         vec![]
     };
-    if let Err(t) = prove_block_real_deal(
-        txn_rlps,
-        block_metadata,
-        trie,
-        contract_codes,
-        storage_tries,
-        withdrawals,
-        final_hash,
-    ) {
-        return Ok(Some(t));
-    };
-
-    Ok(None)
-}
-
-/// Actually prove the block using Plonky2.
-/// If the block fails because of some unknown storage location, return the storage location.
-/// TODO: For now this only runs witness generation, not the prover.
-fn prove_block_real_deal(
-    signed_txns: Vec<Vec<u8>>,
-    block_metadata: BlockMetadata,
-    state_trie: HashedPartialTrie,
-    contract_code: HashMap<H256, Vec<u8>>,
-    storage_tries: Vec<(H256, HashedPartialTrie)>,
-    withdrawals: Vec<(Address, U256)>,
-    final_hash: H256,
-) -> Result<(), (u8, Address, U256, u8)> {
+    let receipts = get_block_receipts(&block, source, options).await?;
+    let (transactions_trie, receipts_trie) =
+        get_txn_and_receipt_tries(&block, &txn_rlps, &receipts)?;
     let inputs = GenerationInputs {
-        signed_txns,
+        signed_txns: txn_rlps,
         tries: TrieInputs {
-            state_trie,
-            transactions_trie: Default::default(),
-            receipts_trie: Default::default(),
+            state_trie: trie,
+            transactions_trie,
+            receipts_trie,
             storage_tries,
         },
         withdrawals,
-        contract_code,
+        contract_code: contract_codes,
         block_metadata,
         addresses: vec![],
     };
-    let proof_run_res = dont_prove_with_outputs::<GoldilocksField, KeccakGoldilocksConfig, 2>(
+
+    Ok((inputs, block, receipts))
+}
+
+/// Run Plonky2 witness generation on the block, and check the state root after the block.
+/// If witness generation fails, return the failure, e.g. the trie node that is missing from the partial tries.
+/// A different state root is a failure too, so that the block isn't proven from inputs already known to be wrong.
+fn prove_block_real_deal(inputs: GenerationInputs, final_hash: H256) -> Result<(), WitnessError> {
+    let pv = generate_witness(inputs)?;
+    let state_root = pv.trie_roots_after.state_root;
+    if state_root != final_hash {
+        return Err(WitnessError::Other(anyhow!(
+            "Witness generation ends with the state root {:?}, expected {:?}",
+            state_root,
+            final_hash
+        )));
+    }
+    println!("Success");
+    Ok(())
+}
+
+/// Run Plonky2 witness generation, and return the public values.
+fn generate_witness(inputs: GenerationInputs) -> Result<PublicValues, WitnessError> {
+    let (pv, _) = dont_prove_with_outputs::<GoldilocksField, KeccakGoldilocksConfig, 2>(
         &AllStark::default(),
         &StarkConfig::standard_fast_config(),
         inputs,
         &mut TimingTree::default(),
-    );
-    if let Err(e) = &proof_run_res {
-        let s = format!("{:?}", e);
-        let re = Regex::new(r"KernelPanic in kernel at pc=delete_hash_node_branch, stack=\[(\d+),[\s\d*,]*\], memory=\[.*\], last_storage_slot=Some\(\((.*), (.*), (.*)\)\)").unwrap();
-        if let Some(cap) = re.captures(&s) {
-            let nibble = cap.get(1).unwrap().as_str().parse().unwrap();
-            let address = Address::from_str(cap.get(2).unwrap().as_str()).unwrap();
-            let slot = U256::from_dec_str(cap.get(3).unwrap().as_str()).unwrap();
-            let depth = cap.get(4).unwrap().as_str().parse().unwrap();
-            return Err((nibble, address, slot, depth));
-        }
-    };
-    if let Ok((pv, _)) = proof_run_res {
-        println!("Success: {}", pv.trie_roots_after.state_root == final_hash);
-    }
-    Ok(())
+    )?;
+    Ok(pv)
 }
 
-// pub async fn prove_txn(hash: H256, provider: &Provider<Http>) -> Result<()> {
-//     let txn = provider.get_transaction(hash);
-//     let txn = txn
-//         .await?
-//         .ok_or_else(|| anyhow!("Transaction not found."))?;
-//     let chain_id = txn.chain_id.unwrap();
-//     let trace = provider
-//         .debug_trace_transaction(hash, tracing_options())
-//         .await?;
-//     let accounts =
-//         if let GethTrace::Known(GethTraceFrame::PreStateTracer(PreStateFrame::Default(accounts))) =
-//             trace
-//         {
-//             accounts.0
-//         } else {
-//             panic!("wtf?");
-//         };
-
-//     let block_number = txn
-//         .block_number
-//         .ok_or_else(|| anyhow!("No block number?"))?;
-//     let mut contract_codes = contract_codes();
-//     let mut storage_tries = vec![];
-//     let mut trie = HashedPartialTrie::new(Node::Empty);
-//     for (address, account) in accounts {
-//         // dbg!(address, &account);
-//         let AccountState { code, storage, .. } = account;
-//         let empty_storage = storage.is_none();
-//         let storage_keys = storage
-//             .unwrap_or_default()
-//             .keys()
-//             .copied()
-//             .collect::<Vec<_>>();
-//         let (proof, storage_proof, storage_hash, _) =
-//             get_proof(address, storage_keys, block_number - 1, provider).await?;
-//         let key = keccak256(address.0);
-//         insert_proof(
-//             &mut trie,
-//             key,
-//             proof,
-//             true,                // fix this
-//             &mut HashSet::new(), // fix this
-//         )?;
-//         if !empty_storage {
-//             let mut storage_trie = HashedPartialTrie::new(Node::Empty);
-//             for sp in storage_proof {
-//                 // dbg!(sp.key, sp.value);
-//                 insert_proof(
-//                     &mut storage_trie,
-//                     keccak256(sp.key.0),
-//                     sp.proof,
-//                     true,                // fix this
-//                     &mut HashSet::new(), // fix this
-//                 )?;
-//             }
-//             assert_eq!(storage_hash, storage_trie.hash());
-//             storage_tries.push((key.into(), storage_trie));
-//         }
-//         if let Some(code) = code {
-//             let code = hex::decode(&code[2..])?;
-//             let codehash = keccak256(&code);
-//             contract_codes.insert(codehash.into(), code);
-//         }
-//     }
-
-//     let (block_metadata, _) = get_block_metadata(block_number, chain_id, provider).await?;
-//     // dbg!(&trie);
-//     let txn_rlp = txn.rlp().to_vec();
-//     prove(txn_rlp, block_metadata, trie, contract_codes, storage_tries);
-
-//     Ok(())
-// }
-
-// fn prove(
-//     txn_rlp: Vec<u8>,
-//     block_metadata: BlockMetadata,
-//     state_trie: HashedPartialTrie,
-//     contract_code: HashMap<H256, Vec<u8>>,
-//     storage_tries: Vec<(H256, HashedPartialTrie)>,
-// ) {
-//     let inputs = GenerationInputs {
-//         signed_txns: vec![txn_rlp],
-//         tries: TrieInputs {
-//             state_trie,
-//             transactions_trie: Default::default(),
-//             receipts_trie: Default::default(),
-//             storage_tries,
-//         },
-//         contract_code,
-//         block_metadata,
-//         addresses: vec![],
-//         withdrawals: vec![],
-//     };
-//     let proof_run_res = dont_prove_with_outputs::<GoldilocksField, KeccakGoldilocksConfig, 2>(
-//         &AllStark::default(),
-//         &StarkConfig::standard_fast_config(),
-//         inputs,
-//         &mut TimingTree::default(),
-//     );
-//     dbg!(proof_run_res);
-// }
+/// Actually prove the block using Plonky2, and check the final state root in the public values.
+/// Proving runs witness generation again, so this should only be called once witness generation succeeds.
+fn prove_block_full(inputs: GenerationInputs, final_hash: H256) -> Result<BlockProof> {
+    let proof = prove::<GoldilocksField, PoseidonGoldilocksConfig, 2>(
+        &AllStark::default(),
+        &StarkConfig::standard_fast_config(),
+        inputs,
+        &mut TimingTree::default(),
+    )?;
+    let state_root = proof.public_values.trie_roots_after.state_root;
+    ensure!(
+        state_root == final_hash,
+        "Proof has final state root {:?}, expected {:?}",
+        state_root,
+        final_hash
+    );
+    Ok(proof)
+}
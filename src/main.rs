@@ -1,18 +1,211 @@
-use anyhow::Result;
-use eth_proof::prove_block_loop;
+use anyhow::{bail, ensure, Context, Result};
+use eth_proof::aggregation::recursive_circuits;
+use eth_proof::bundle::{fetch_bundle, WitnessBundle};
+use eth_proof::cache::CachedSource;
+use eth_proof::chain::{ChainConfig, ForkActivation};
+use eth_proof::data_source::{BlockDataSource, RoutedSource};
+use eth_proof::preflight::check_node;
+use eth_proof::proof_file::ProofFile;
+use eth_proof::retry::{RetryPolicy, RetrySource};
+use eth_proof::txns::prove_block_txns_loop;
 use eth_proof::utils::init_env_logger;
+use eth_proof::{get_chain_config, prove_block_loop, ProverOptions};
 use ethers::prelude::*;
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::time::Duration;
+
+/// Usage of the command line.
+const USAGE: &str = "Usage: eth-proof <block number> | fetch <block number> <bundle file> | replay <bundle file> | verify <proof file>";
 
 #[tokio::main]
 async fn main() -> Result<()> {
     init_env_logger();
-    let rpc_url = std::env::var("RPC_URL")?;
-    let provider = Provider::<Http>::try_from(&rpc_url)?;
 
     let args = std::env::args().collect::<Vec<_>>();
-    println!("Proving block {}", args[1]);
-    prove_block_loop(args[1].parse().unwrap(), &provider).await?;
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    let options = options()?;
+    match args.get(1..).unwrap_or_default() {
+        ["fetch", block_number, path] => {
+            let provider = provider().await?;
+            let block_number = block_number.parse()?;
+            let chain = get_chain_config(&provider, &options).await?;
+            check_node(block_number, provider.uncached(), &chain, &options).await?;
+            println!("Fetching block {}", block_number);
+            let bundle = fetch_bundle(block_number, &provider, &options).await?;
+            bundle.save(path)?;
+        }
+        ["verify", path] => {
+            let file = ProofFile::load(path)?;
+            println!("Verifying the proof of block {}", file.block_number);
+            file.verify(&recursive_circuits())?;
+            println!(
+                "Verified the proof of block {} from state root {:?} to {:?}",
+                file.block_number,
+                file.public_values.trie_roots_before.state_root,
+                file.public_values.trie_roots_after.state_root
+            );
+        }
+        ["replay", path] => {
+            let bundle = WitnessBundle::load(path)?;
+            println!("Replaying block {}", bundle.block_number);
+            prove(bundle.block_number, &bundle, &options).await?;
+        }
+        [block_number] => {
+            let block_number = block_number.parse().context(USAGE)?;
+            let provider = provider().await?;
+            let chain = get_chain_config(&provider, &options).await?;
+            check_node(block_number, provider.uncached(), &chain, &options).await?;
+            println!("Proving block {}", block_number);
+            prove(block_number, &provider, &options).await?;
+        }
+        _ => bail!(USAGE),
+    }
+
+    Ok(())
+}
 
+/// Prove a block, at once or one transaction at a time depending on the options.
+/// The proof of a block proven one transaction at a time is written to `PROOF_FILE` if it is set.
+/// The recursive circuits aggregating the transaction proofs are built once, before witness generation.
+async fn prove<D: BlockDataSource>(
+    block_number: u64,
+    source: &D,
+    options: &ProverOptions,
+) -> Result<()> {
+    let proof_file = std::env::var("PROOF_FILE").ok();
+    ensure!(
+        proof_file.is_none() || (options.per_txn && options.full_proof),
+        "Only proofs generated with PER_TXN=true and FULL_PROOF=true can be written to PROOF_FILE"
+    );
+    if options.per_txn {
+        let circuits = options.full_proof.then(recursive_circuits);
+        if let Some(proof) =
+            prove_block_txns_loop(block_number, source, options, circuits.as_ref()).await?
+        {
+            println!(
+                "Proved block {} from state root {:?} to {:?}",
+                block_number, proof.before.state_root, proof.after.state_root
+            );
+            if let Some(path) = proof_file {
+                ProofFile::new(block_number, &proof).save(&path)?;
+                println!("Wrote the proof to {}", path);
+            }
+        }
+    } else if prove_block_loop(block_number, source, options)
+        .await?
+        .is_some()
+    {
+        println!("Proved block {}", block_number);
+    }
     Ok(())
 }
+
+type Endpoint = RetrySource<Provider<Http>>;
+
+/// Build the data source for the nodes given in the environment, caching their responses in `RPC_CACHE_DIR`, with a
+/// subdirectory per chain.
+/// Blocks and transactions are fetched from `BLOCKS_RPC_URL`, traces from `TRACE_RPC_URL` and proofs from
+/// `PROOF_RPC_URL`, each defaulting to `RPC_URL`.
+/// Set `NO_CACHE=true` to bypass the cache, or `CLEAR_CACHE=true` to empty it before running.
+async fn provider() -> Result<CachedSource<RoutedSource<Endpoint, Endpoint, Endpoint>>> {
+    let mut endpoints = HashMap::new();
+    let provider = RoutedSource {
+        blocks: endpoint("BLOCKS_RPC_URL", &mut endpoints)?,
+        traces: endpoint("TRACE_RPC_URL", &mut endpoints)?,
+        proofs: endpoint("PROOF_RPC_URL", &mut endpoints)?,
+    };
+    if env_flag("NO_CACHE")? {
+        return Ok(CachedSource::bypass(provider));
+    }
+    let cache_dir = std::env::var("RPC_CACHE_DIR").unwrap_or_else(|_| "rpc_cache".to_string());
+    let source = CachedSource::new(provider, cache_dir).await?;
+    if env_flag("CLEAR_CACHE")? {
+        source.clear()?;
+    }
+    Ok(source)
+}
+
+/// Connect to the node at the URL in the environment variable `var`, or at `RPC_URL` if it is not set.
+/// `endpoints` holds the endpoints already connected, by URL, so that all requests to the same node share the same
+/// rate limit.
+fn endpoint(var: &str, endpoints: &mut HashMap<String, Endpoint>) -> Result<Endpoint> {
+    let rpc_url = std::env::var(var).or_else(|_| std::env::var("RPC_URL"))?;
+    if let Some(endpoint) = endpoints.get(&rpc_url) {
+        return Ok(endpoint.clone());
+    }
+    let endpoint = RetrySource::new(Provider::<Http>::try_from(&rpc_url)?, retry_policy()?);
+    endpoints.insert(rpc_url, endpoint.clone());
+    Ok(endpoint)
+}
+
+/// Read the RPC retry policy from the environment, falling back to the defaults.
+fn retry_policy() -> Result<RetryPolicy> {
+    let mut policy = RetryPolicy::default();
+    if let Ok(max_attempts) = std::env::var("RPC_MAX_ATTEMPTS") {
+        policy.max_attempts = max_attempts.parse()?;
+    }
+    if let Ok(requests_per_second) = std::env::var("RPC_REQUESTS_PER_SECOND") {
+        policy.requests_per_second = Some(requests_per_second.parse()?);
+    }
+    Ok(policy)
+}
+
+/// Read a boolean flag from the environment, defaulting to `false`.
+fn env_flag(name: &str) -> Result<bool> {
+    Ok(match std::env::var(name) {
+        Ok(v) => v.parse()?,
+        Err(_) => false,
+    })
+}
+
+/// Read the prover options from the environment, falling back to the defaults.
+fn options() -> Result<ProverOptions> {
+    let mut options = ProverOptions::default();
+    if let Ok(trace_block) = std::env::var("TRACE_BLOCK") {
+        options.trace_block = trace_block.parse()?;
+    }
+    if let Ok(max_concurrent_proofs) = std::env::var("MAX_CONCURRENT_PROOFS") {
+        options.max_concurrent_proofs = max_concurrent_proofs.parse()?;
+    }
+    if let Ok(predict_missing_nodes) = std::env::var("PREDICT_MISSING_NODES") {
+        options.predict_missing_nodes = predict_missing_nodes.parse()?;
+    }
+    if let Ok(full_proof) = std::env::var("FULL_PROOF") {
+        options.full_proof = full_proof.parse()?;
+    }
+    if let Ok(per_txn) = std::env::var("PER_TXN") {
+        options.per_txn = per_txn.parse()?;
+    }
+    if let Ok(seed) = std::env::var("GRIND_SEED") {
+        options.grind.seed = seed.parse()?;
+    }
+    if let Ok(max_attempts) = std::env::var("GRIND_MAX_ATTEMPTS") {
+        options.grind.max_attempts = max_attempts.parse()?;
+    }
+    if let Ok(timeout) = std::env::var("GRIND_TIMEOUT_SECS") {
+        options.grind.timeout = Duration::from_secs(timeout.parse()?);
+    }
+    if let Ok(attempts) = std::env::var("PREFETCH_GRIND_ATTEMPTS") {
+        options.prefetch_grind_attempts = attempts.parse()?;
+    }
+    if let Ok(threads) = std::env::var("GRIND_THREADS") {
+        options.grind.threads = threads.parse()?;
+    }
+    options.grind.preimage_dir = Some(
+        std::env::var("PREIMAGE_DIR")
+            .unwrap_or_else(|_| "preimages".to_string())
+            .into(),
+    );
+    if let Ok(chain_id) = std::env::var("CHAIN_ID") {
+        let mut chain = ChainConfig::from_chain_id(U256::from_dec_str(&chain_id)?);
+        if let Ok(shanghai_block) = std::env::var("SHANGHAI_BLOCK") {
+            chain.shanghai = ForkActivation::Block(shanghai_block.parse()?);
+        }
+        if let Ok(shanghai_time) = std::env::var("SHANGHAI_TIME") {
+            chain.shanghai = ForkActivation::Timestamp(shanghai_time.parse()?);
+        }
+        options.chain = Some(chain);
+    }
+    Ok(options)
+}
@@ -1,155 +1,1030 @@
 use std::collections::HashSet;
+use std::sync::Arc;
 
-use anyhow::Result;
 use eth_trie_utils::nibbles::Nibbles;
-use eth_trie_utils::partial_trie::{HashedPartialTrie, PartialTrie};
+use eth_trie_utils::partial_trie::{HashedPartialTrie, Node, PartialTrie};
 use ethers::prelude::*;
-use ethers::utils::rlp;
+use ethers::utils::keccak256;
+use ethers::utils::rlp::{self, Rlp};
+use thiserror::Error;
+
+/// Root hash of the empty trie, i.e., Keccak of the RLP encoding of empty bytes.
+pub const EMPTY_TRIE_HASH: H256 = H256([
+    86, 232, 31, 23, 27, 204, 85, 166, 255, 131, 69, 230, 146, 192, 248, 110, 91, 72, 224, 27, 153,
+    108, 173, 192, 1, 98, 47, 181, 227, 99, 180, 33,
+]);
+
+/// Type of a MPT node in a proof.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeType {
+    Branch,
+    Extension,
+    Leaf,
+}
+
+/// Error returned when a MPT proof can't be inserted in a partial trie.
+#[derive(Debug, Error)]
+pub enum ProofInsertionError {
+    #[error("proof node {index} is not a valid RLP list: {error}")]
+    InvalidRlp {
+        index: usize,
+        error: rlp::DecoderError,
+    },
+    #[error("proof node {index} has {len} items, expected 17 for a branch or 2 for an extension or leaf")]
+    InvalidNodeLength { index: usize, len: usize },
+    #[error("proof node {index} has an invalid hex-prefix encoded path")]
+    InvalidPath { index: usize },
+    #[error("{node_type:?} node {index} goes past the end of the key")]
+    KeyTooShort { index: usize, node_type: NodeType },
+    #[error("{node_type:?} node {index} diverges from the key at nibble position {position}: expected {expected:x}, got {actual:x}")]
+    KeyMismatch {
+        index: usize,
+        node_type: NodeType,
+        position: usize,
+        expected: u8,
+        actual: u8,
+    },
+    #[error("leaf node {index} ends at nibble position {position}, before the end of the key")]
+    LeafTooShort { index: usize, position: usize },
+    #[error("proof node {index} is the empty node, which is only valid as the whole exclusion proof of the empty trie")]
+    UnexpectedEmptyNode { index: usize },
+}
+
+/// Error returned when a MPT proof doesn't prove the value of a key under a given root.
+#[derive(Debug, Error)]
+pub enum ProofVerificationError {
+    #[error("proof ends at node {index}, before reaching the key")]
+    MissingNode { index: usize },
+    #[error("proof node {index} has hash {actual:?}, expected {expected:?}")]
+    HashMismatch {
+        index: usize,
+        expected: H256,
+        actual: H256,
+    },
+    #[error("proof node {index} is not a valid MPT node: {error}")]
+    InvalidNode {
+        index: usize,
+        error: rlp::DecoderError,
+    },
+    #[error("proof node {index} has {len} items, expected 17 for a branch or 2 for an extension or leaf")]
+    InvalidNodeLength { index: usize, len: usize },
+    #[error("proof node {index} has an invalid hex-prefix encoded path")]
+    InvalidPath { index: usize },
+}
+
+/// Error returned when a key can't be deleted from a partial trie.
+#[derive(Debug, Error)]
+pub enum DeletionError {
+    #[error("the key goes through the hash node at {0:?}")]
+    HashNode(Nibbles),
+    #[error("the branch node at {branch:?} collapses, and its remaining child {nibble:x} is a hash node")]
+    CollapsingHashNode { branch: Nibbles, nibble: u8 },
+}
+
+/// Reference to a child node: either its hash, or the node itself when its encoding is shorter than 32 bytes.
+enum ChildRef {
+    Hash(H256),
+    Embedded(Vec<u8>),
+}
+
+/// Decode a child reference of a node. Return `None` for an empty child.
+fn decode_child(child: Rlp) -> Result<Option<ChildRef>, rlp::DecoderError> {
+    if child.is_list() {
+        return Ok(Some(ChildRef::Embedded(child.as_raw().to_vec())));
+    }
+    let data = child.data()?;
+    match data.len() {
+        0 => Ok(None),
+        32 => Ok(Some(ChildRef::Hash(H256::from_slice(data)))),
+        _ => Err(rlp::DecoderError::Custom(
+            "child is neither a 32-byte hash nor an embedded node",
+        )),
+    }
+}
+
+/// Decode a hex-prefix encoded path into its nibbles. Also return whether it is the path of a leaf.
+/// Return `None` if the encoding is invalid.
+fn decode_path(encoded_path: &[u8]) -> Option<(Vec<u8>, bool)> {
+    let flag = *encoded_path.first()? >> 4;
+    if flag > 3 {
+        return None;
+    }
+    let mut path = vec![];
+    // Odd-length paths have their first nibble in the low nibble of the flag byte.
+    if flag & 1 == 1 {
+        path.push(encoded_path[0] & 0xf);
+    }
+    for &byte in &encoded_path[1..] {
+        path.push(byte >> 4);
+        path.push(byte & 0xf);
+    }
+    Some((path, flag & 2 == 2))
+}
+
+/// Verify a MPT proof for `key` against the trie root `root`, walking the node hashes from the root.
+/// Return the value of the key, or `None` if the proof shows that the key is not in the trie.
+pub fn verify_proof(
+    root: H256,
+    key: [u8; 32],
+    proof: &[Bytes],
+) -> Result<Option<Vec<u8>>, ProofVerificationError> {
+    let key_nibbles = key
+        .iter()
+        .flat_map(|&b| [b >> 4, b & 0xf])
+        .collect::<Vec<_>>();
+    let mut pos = 0;
+    let mut index = 0;
+    let mut next = ChildRef::Hash(root);
+    loop {
+        let node = match next {
+            ChildRef::Hash(hash) => {
+                let Some(node) = proof.get(index) else {
+                    if hash == EMPTY_TRIE_HASH {
+                        return Ok(None);
+                    }
+                    return Err(ProofVerificationError::MissingNode { index });
+                };
+                let actual = H256(keccak256(node));
+                if actual != hash {
+                    return Err(ProofVerificationError::HashMismatch {
+                        index,
+                        expected: hash,
+                        actual,
+                    });
+                }
+                index += 1;
+                node.to_vec()
+            }
+            ChildRef::Embedded(node) => node,
+        };
+        // Errors in embedded nodes are reported at the index of the proof node containing them.
+        let node_index = index.saturating_sub(1);
+        let rlp = Rlp::new(&node);
+        if rlp.is_empty() {
+            return Ok(None);
+        }
+        let invalid = |error| ProofVerificationError::InvalidNode {
+            index: node_index,
+            error,
+        };
+        match rlp.item_count().map_err(invalid)? {
+            17 => {
+                let Some(&nibble) = key_nibbles.get(pos) else {
+                    return Err(ProofVerificationError::InvalidPath { index: node_index });
+                };
+                pos += 1;
+                match decode_child(rlp.at(nibble as usize).map_err(invalid)?).map_err(invalid)? {
+                    Some(child) => next = child,
+                    None => return Ok(None),
+                }
+            }
+            2 => {
+                let encoded_path = rlp.at(0).and_then(|p| p.data()).map_err(invalid)?;
+                let (path, is_leaf) = decode_path(encoded_path)
+                    .ok_or(ProofVerificationError::InvalidPath { index: node_index })?;
+                let rest = &key_nibbles[pos..];
+                if is_leaf {
+                    if rest != path {
+                        return Ok(None);
+                    }
+                    let value = rlp.at(1).and_then(|v| v.data()).map_err(invalid)?;
+                    return Ok(Some(value.to_vec()));
+                }
+                if !rest.starts_with(&path) {
+                    return Ok(None);
+                }
+                pos += path.len();
+                match decode_child(rlp.at(1).map_err(invalid)?).map_err(invalid)? {
+                    Some(child) => next = child,
+                    None => return Err(ProofVerificationError::InvalidPath { index: node_index }),
+                }
+            }
+            len => {
+                return Err(ProofVerificationError::InvalidNodeLength {
+                    index: node_index,
+                    len,
+                })
+            }
+        }
+    }
+}
+
+/// Consume the hex-prefix encoded path of an extension or leaf node, appending its nibbles to `current_prefix`.
+/// If `check` is set, the path has to match the next nibbles of the key.
+fn consume_path(
+    encoded_path: &[u8],
+    nibbles: &mut Nibbles,
+    current_prefix: &mut Nibbles,
+    check: bool,
+    index: usize,
+    node_type: NodeType,
+) -> Result<(), ProofInsertionError> {
+    let (path, _) = decode_path(encoded_path).ok_or(ProofInsertionError::InvalidPath { index })?;
+    for b in path {
+        if nibbles.count == 0 {
+            return Err(ProofInsertionError::KeyTooShort { index, node_type });
+        }
+        let position = 64 - nibbles.count;
+        let nibble = nibbles.pop_next_nibble_front();
+        if check && b != nibble {
+            return Err(ProofInsertionError::KeyMismatch {
+                index,
+                node_type,
+                position,
+                expected: nibble,
+                actual: b,
+            });
+        }
+        current_prefix.push_nibble_back(b);
+    }
+    Ok(())
+}
+
+/// Insert a node given by its RLP encoding in the subtrie at `prefix`: its leaves, and its hashed children as hash nodes.
+/// Used for embedded nodes, i.e., nodes whose encoding is shorter than 32 bytes. Embedded nodes are contained in their
+/// parent and don't appear separately in proofs, and they can't be replaced by a hash node without changing the
+/// encoding of their parent. Also used to expand a hash node of the trie once its preimage is known.
+/// Errors are reported at the proof node `index`.
+pub fn insert_node(
+    trie: &mut HashedPartialTrie,
+    prefix: Nibbles,
+    node: &[u8],
+    index: usize,
+) -> Result<(), ProofInsertionError> {
+    let invalid = |error| ProofInsertionError::InvalidRlp { index, error };
+    let rlp = Rlp::new(node);
+    match rlp.item_count().map_err(invalid)? {
+        17 => {
+            for i in 0..16 {
+                let mut child_prefix = prefix;
+                child_prefix.push_nibble_back(i);
+                match decode_child(rlp.at(i as usize).map_err(invalid)?).map_err(invalid)? {
+                    Some(ChildRef::Hash(hash)) => {
+                        trie.insert(child_prefix, hash);
+                    }
+                    Some(ChildRef::Embedded(child)) => {
+                        insert_node(trie, child_prefix, &child, index)?
+                    }
+                    None => {}
+                }
+            }
+        }
+        2 => {
+            let encoded_path = rlp.at(0).and_then(|p| p.data()).map_err(invalid)?;
+            let (path, is_leaf) =
+                decode_path(encoded_path).ok_or(ProofInsertionError::InvalidPath { index })?;
+            let mut prefix = prefix;
+            for nibble in path {
+                prefix.push_nibble_back(nibble);
+            }
+            if is_leaf {
+                let value = rlp.at(1).and_then(|v| v.data()).map_err(invalid)?;
+                trie.insert(prefix, value.to_vec());
+            } else {
+                match decode_child(rlp.at(1).map_err(invalid)?).map_err(invalid)? {
+                    Some(ChildRef::Hash(hash)) => {
+                        trie.insert(prefix, hash);
+                    }
+                    Some(ChildRef::Embedded(child)) => insert_node(trie, prefix, &child, index)?,
+                    None => return Err(ProofInsertionError::InvalidPath { index }),
+                }
+            }
+        }
+        len => return Err(ProofInsertionError::InvalidNodeLength { index, len }),
+    }
+    Ok(())
+}
+
+/// Return the hash of the hash node at `prefix` in the trie, if there is one.
+pub fn hash_node_at(trie: &HashedPartialTrie, mut prefix: Nibbles) -> Option<H256> {
+    let mut node = trie;
+    loop {
+        match &**node {
+            Node::Hash(hash) if prefix.count == 0 => return Some(*hash),
+            Node::Branch { children, .. } if prefix.count > 0 => {
+                node = &children[prefix.pop_next_nibble_front() as usize];
+            }
+            Node::Extension { nibbles, child } => {
+                let mut path = *nibbles;
+                while path.count > 0 {
+                    if prefix.count == 0
+                        || path.pop_next_nibble_front() != prefix.pop_next_nibble_front()
+                    {
+                        return None;
+                    }
+                }
+                node = child;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Return the hash node that the path of `key` goes through in the trie, if there is one, as the path of its parent
+/// node and its nibble in that node. A key behind a hash node can't be read nor written until the preimage of the hash
+/// node is inserted.
+pub fn hash_node_on_path(trie: &HashedPartialTrie, mut key: Nibbles) -> Option<(Nibbles, u8)> {
+    let mut node = trie;
+    let mut prefix = Nibbles {
+        count: 0,
+        packed: U256::zero(),
+    };
+    // Parent path and nibble of the current node.
+    let mut parent = None;
+    loop {
+        match &**node {
+            Node::Hash(_) => return parent,
+            Node::Branch { children, .. } if key.count > 0 => {
+                let nibble = key.pop_next_nibble_front();
+                parent = Some((prefix, nibble));
+                prefix.push_nibble_back(nibble);
+                node = &children[nibble as usize];
+            }
+            Node::Extension { nibbles, child } => {
+                let mut path = *nibbles;
+                while path.count > 0 {
+                    let nibble = path.pop_next_nibble_front();
+                    if key.count == 0 || nibble != key.pop_next_nibble_front() {
+                        return None;
+                    }
+                    parent = Some((prefix, nibble));
+                    prefix.push_nibble_back(nibble);
+                }
+                node = child;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Delete a key from a partial trie, collapsing the branch nodes left with a single child.
+/// Deleting a key that is not in the trie leaves it unchanged. Fails if the key or the remaining child of a collapsing
+/// branch node is behind a hash node, in which case the preimage of the hash node must be inserted first.
+pub fn delete_key(trie: &mut HashedPartialTrie, key: Nibbles) -> Result<(), DeletionError> {
+    let empty_prefix = Nibbles {
+        count: 0,
+        packed: U256::zero(),
+    };
+    *trie = delete_from(trie, empty_prefix, key)?;
+    Ok(())
+}
+
+/// Return the subtrie `node` at `prefix` with the rest of the key `key` deleted.
+fn delete_from(
+    node: &HashedPartialTrie,
+    prefix: Nibbles,
+    mut key: Nibbles,
+) -> Result<HashedPartialTrie, DeletionError> {
+    match &**node {
+        Node::Empty => Ok(node.clone()),
+        Node::Hash(_) => Err(DeletionError::HashNode(prefix)),
+        Node::Leaf { nibbles, .. } if *nibbles == key => Ok(HashedPartialTrie::new(Node::Empty)),
+        Node::Leaf { .. } => Ok(node.clone()),
+        Node::Extension { nibbles, child } => {
+            let mut path = *nibbles;
+            while path.count > 0 {
+                if key.count == 0 || path.pop_next_nibble_front() != key.pop_next_nibble_front() {
+                    return Ok(node.clone());
+                }
+            }
+            let child = delete_from(child, concat_nibbles(prefix, *nibbles), key)?;
+            Ok(prepend_path(*nibbles, child))
+        }
+        // Keys have a fixed length, so branch nodes never hold a value.
+        Node::Branch { .. } if key.count == 0 => Ok(node.clone()),
+        Node::Branch { children, value } => {
+            let nibble = key.pop_next_nibble_front();
+            let mut child_prefix = prefix;
+            child_prefix.push_nibble_back(nibble);
+            let child = delete_from(&children[nibble as usize], child_prefix, key)?;
+            let mut children = children.clone();
+            children[nibble as usize] = Arc::new(Box::new(child));
+            let remaining = (0..16)
+                .filter(|&i| !matches!(&***children[i], Node::Empty))
+                .collect::<Vec<_>>();
+            match remaining[..] {
+                [i] if value.is_empty() => {
+                    let child: &HashedPartialTrie = &children[i];
+                    if let Node::Hash(_) = &**child {
+                        return Err(DeletionError::CollapsingHashNode {
+                            branch: prefix,
+                            nibble: i as u8,
+                        });
+                    }
+                    let mut path = Nibbles {
+                        count: 0,
+                        packed: U256::zero(),
+                    };
+                    path.push_nibble_back(i as u8);
+                    Ok(prepend_path(path, child.clone()))
+                }
+                _ => Ok(HashedPartialTrie::new(Node::Branch {
+                    children,
+                    value: value.clone(),
+                })),
+            }
+        }
+    }
+}
+
+/// Prepend a path to a node, merging it into the path of a leaf or extension node.
+fn prepend_path(path: Nibbles, node: HashedPartialTrie) -> HashedPartialTrie {
+    match &*node {
+        Node::Empty => node,
+        Node::Leaf { nibbles, value } => HashedPartialTrie::new(Node::Leaf {
+            nibbles: concat_nibbles(path, *nibbles),
+            value: value.clone(),
+        }),
+        Node::Extension { nibbles, child } => HashedPartialTrie::new(Node::Extension {
+            nibbles: concat_nibbles(path, *nibbles),
+            child: child.clone(),
+        }),
+        _ if path.count == 0 => node,
+        _ => HashedPartialTrie::new(Node::Extension {
+            nibbles: path,
+            child: Arc::new(Box::new(node)),
+        }),
+    }
+}
+
+/// Concatenation of two nibble paths.
+pub(crate) fn concat_nibbles(mut prefix: Nibbles, mut suffix: Nibbles) -> Nibbles {
+    while suffix.count > 0 {
+        prefix.push_nibble_back(suffix.pop_next_nibble_front());
+    }
+    prefix
+}
 
 /// Reconstruct a Merkle-Patricia partial trie from a MPT proof.
 /// Can be an account proof for the state MPT or a storage proof for the storage MPT.
+/// `insert_leaf` is whether the key is in the trie. Otherwise the proof is an exclusion proof, which ends either
+/// at a branch node with an empty child at the next nibble of the key, at a leaf or extension node whose path
+/// diverges from the key, or is empty if the trie is empty. The nodes ending an exclusion proof are inserted as they are.
 pub fn insert_proof(
     trie: &mut HashedPartialTrie,
     key: [u8; 32],
     proof: Vec<Bytes>,
     insert_leaf: bool,
     dont_touch_these_nibbles: &mut HashSet<Nibbles>,
-) -> Result<()> {
-    let mut nibbles = Nibbles::from_bytes_be(&key)?;
+) -> Result<(), ProofInsertionError> {
+    let mut nibbles = Nibbles::from_bytes_be(&key).expect("32-byte keys always fit in nibbles");
     let mut current_prefix = Nibbles {
         count: 0,
         packed: U256::zero(),
     };
     let proof_len = proof.len();
     for (p_ind, p) in proof.into_iter().enumerate() {
-        let a = rlp::decode_list::<Vec<u8>>(&p);
-        match a.len() {
+        let invalid = |error| ProofInsertionError::InvalidRlp {
+            index: p_ind,
+            error,
+        };
+        let node = Rlp::new(&p);
+        // Proof of a key in the empty trie. Some nodes return it as the empty node instead of an empty proof.
+        if node.is_empty() {
+            if insert_leaf || proof_len != 1 {
+                return Err(ProofInsertionError::UnexpectedEmptyNode { index: p_ind });
+            }
+            continue;
+        }
+        match node.item_count().map_err(invalid)? {
             17 => {
+                if nibbles.count == 0 {
+                    return Err(ProofInsertionError::KeyTooShort {
+                        index: p_ind,
+                        node_type: NodeType::Branch,
+                    });
+                }
                 let nibble = nibbles.pop_next_nibble_front();
                 for i in 0..16 {
                     let mut new_prefix = current_prefix;
                     new_prefix.push_nibble_back(i);
-                    if insert_leaf && i == nibble {
-                        dont_touch_these_nibbles.insert(new_prefix);
-                        continue;
+                    let child =
+                        decode_child(node.at(i as usize).map_err(invalid)?).map_err(invalid)?;
+                    if i == nibble {
+                        // The rest of the path is inside the embedded child, not in the next proof nodes.
+                        if let Some(ChildRef::Embedded(child)) = &child {
+                            insert_node(trie, new_prefix, child, p_ind)?;
+                        }
                     }
-                    // if !insert_leaf && (p_ind < proof_len - 1) && i == nibble {
-                    //     continue;
-                    // }
-                    if dont_touch_these_nibbles.contains(&new_prefix) {
+                    // The child on the path of the key is expanded by the next proof nodes, so it is not inserted as a hash.
+                    // In an exclusion proof ending at this branch, the child on the path is empty and there is nothing to insert.
+                    if i == nibble && (insert_leaf || p_ind < proof_len - 1) {
+                        dont_touch_these_nibbles.insert(new_prefix);
                         continue;
                     }
-                    if !a[i as usize].is_empty()
-                        && !trie.is_not_empty_or_hash(&mut new_prefix.clone())
+                    if dont_touch_these_nibbles.contains(&new_prefix)
+                        || trie.is_not_empty_or_hash(&mut new_prefix.clone())
                     {
-                        let hash = H256::from_slice(&a[i as usize]);
-                        trie.insert(new_prefix, hash);
+                        continue;
                     }
-                }
-                current_prefix.push_nibble_back(nibble);
-            }
-            2 => match a[0][0] >> 4 {
-                0 => {
-                    let ext_prefix = &a[0][1..];
-                    for &byte in ext_prefix {
-                        let b = byte >> 4;
-                        let nibble = nibbles.pop_next_nibble_front();
-                        if insert_leaf {
-                            assert_eq!(b, nibble);
+                    match child {
+                        Some(ChildRef::Hash(hash)) => {
+                            trie.insert(new_prefix, hash);
                         }
-                        current_prefix.push_nibble_back(b);
-                        let b = byte & 0xf;
-                        let nibble = nibbles.pop_next_nibble_front();
-                        if insert_leaf {
-                            assert_eq!(b, nibble);
+                        Some(ChildRef::Embedded(child)) => {
+                            insert_node(trie, new_prefix, &child, p_ind)?
                         }
-                        current_prefix.push_nibble_back(b);
-                    }
-                    if !insert_leaf && p_ind == proof_len - 1 {
-                        assert!(!a[1].is_empty());
-                        trie.insert(current_prefix, H256::from_slice(&a[1]));
+                        None => {}
                     }
                 }
-                1 => {
-                    let b = a[0][0] & 0xf;
-                    let nibble = nibbles.pop_next_nibble_front();
-                    if insert_leaf {
-                        assert_eq!(b, nibble);
-                    }
-                    current_prefix.push_nibble_back(b);
-                    let ext_prefix = &a[0][1..];
-                    for &byte in ext_prefix {
-                        let b = byte >> 4;
-                        let nibble = nibbles.pop_next_nibble_front();
-                        if insert_leaf {
-                            assert_eq!(b, nibble);
-                        }
-                        current_prefix.push_nibble_back(b);
-                        let b = byte & 0xf;
-                        let nibble = nibbles.pop_next_nibble_front();
-                        if insert_leaf {
-                            assert_eq!(b, nibble);
-                        }
-                        current_prefix.push_nibble_back(b);
-                    }
-                    if !insert_leaf && p_ind == proof_len - 1 {
-                        assert!(!a[1].is_empty());
-                        trie.insert(current_prefix, H256::from_slice(&a[1]));
-                    }
+                current_prefix.push_nibble_back(nibble);
+            }
+            2 => {
+                let encoded_path = node.at(0).and_then(|p| p.data()).map_err(invalid)?;
+                if encoded_path.is_empty() {
+                    return Err(ProofInsertionError::InvalidPath { index: p_ind });
                 }
-                2 => {
-                    let leaf_prefix = &a[0][1..];
-                    for &byte in leaf_prefix {
-                        let b = byte >> 4;
-                        let nibble = nibbles.pop_next_nibble_front();
-                        if insert_leaf {
-                            assert_eq!(b, nibble);
-                        }
-                        current_prefix.push_nibble_back(b);
-                        let b = byte & 0xf;
-                        let nibble = nibbles.pop_next_nibble_front();
-                        if insert_leaf {
-                            assert_eq!(b, nibble);
+                match encoded_path[0] >> 4 {
+                    0 | 1 => {
+                        consume_path(
+                            encoded_path,
+                            &mut nibbles,
+                            &mut current_prefix,
+                            insert_leaf,
+                            p_ind,
+                            NodeType::Extension,
+                        )?;
+                        match decode_child(node.at(1).map_err(invalid)?).map_err(invalid)? {
+                            // The rest of the path is inside the embedded child, not in the next proof nodes.
+                            Some(ChildRef::Embedded(child)) => {
+                                insert_node(trie, current_prefix, &child, p_ind)?
+                            }
+                            Some(ChildRef::Hash(hash)) => {
+                                if !insert_leaf && p_ind == proof_len - 1 {
+                                    trie.insert(current_prefix, hash);
+                                }
+                            }
+                            None => return Err(ProofInsertionError::InvalidPath { index: p_ind }),
                         }
-                        current_prefix.push_nibble_back(b);
                     }
-                    if insert_leaf {
-                        assert_eq!(current_prefix, Nibbles::from_bytes_be(&key)?);
-                    }
-                    trie.insert(current_prefix, a[1].clone());
-                }
-                3 => {
-                    let b = a[0][0] & 0xf;
-                    let nibble = nibbles.pop_next_nibble_front();
-                    if insert_leaf {
-                        assert_eq!(b, nibble);
-                    }
-                    current_prefix.push_nibble_back(b);
-                    let leaf_prefix = &a[0][1..];
-                    for &byte in leaf_prefix {
-                        let b = byte >> 4;
-                        let nibble = nibbles.pop_next_nibble_front();
-                        if insert_leaf {
-                            assert_eq!(b, nibble);
-                        }
-                        current_prefix.push_nibble_back(b);
-                        let b = byte & 0xf;
-                        let nibble = nibbles.pop_next_nibble_front();
-                        if insert_leaf {
-                            assert_eq!(b, nibble);
+                    2 | 3 => {
+                        consume_path(
+                            encoded_path,
+                            &mut nibbles,
+                            &mut current_prefix,
+                            insert_leaf,
+                            p_ind,
+                            NodeType::Leaf,
+                        )?;
+                        if insert_leaf && nibbles.count != 0 {
+                            return Err(ProofInsertionError::LeafTooShort {
+                                index: p_ind,
+                                position: current_prefix.count,
+                            });
                         }
-                        current_prefix.push_nibble_back(b);
+                        let value = node.at(1).and_then(|v| v.data()).map_err(invalid)?;
+                        trie.insert(current_prefix, value.to_vec());
                     }
-                    if insert_leaf {
-                        assert_eq!(current_prefix, Nibbles::from_bytes_be(&key)?);
-                    }
-                    trie.insert(current_prefix, a[1].clone());
+                    _ => return Err(ProofInsertionError::InvalidPath { index: p_ind }),
                 }
-                _ => panic!("wtf?"),
-            },
-            _ => panic!("wtf?"),
+            }
+            len => {
+                return Err(ProofInsertionError::InvalidNodeLength { index: p_ind, len });
+            }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use ethers::utils::rlp::RlpStream;
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// Hex-prefix encoding of a path.
+    fn hex_prefix(path: &[u8], is_leaf: bool) -> Vec<u8> {
+        let flag = (if is_leaf { 2 } else { 0 }) + (path.len() % 2) as u8;
+        let mut encoded = vec![];
+        let rest = if path.len() % 2 == 1 {
+            encoded.push((flag << 4) | path[0]);
+            &path[1..]
+        } else {
+            encoded.push(flag << 4);
+            path
+        };
+        encoded.extend(rest.chunks(2).map(|c| (c[0] << 4) | c[1]));
+        encoded
+    }
+
+    fn leaf(path: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(2);
+        stream.append(&hex_prefix(path, true));
+        stream.append(&value.to_vec());
+        stream.out().to_vec()
+    }
+
+    /// Append a reference to a child node: the node itself if its encoding is shorter than 32 bytes, its hash otherwise.
+    fn append_child(stream: &mut RlpStream, child: &[u8]) {
+        if child.len() < 32 {
+            stream.append_raw(child, 1);
+        } else {
+            stream.append(&keccak256(child).to_vec());
+        }
+    }
+
+    fn branch(children: &[(usize, Vec<u8>)]) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(17);
+        for i in 0..16 {
+            match children.iter().find(|(j, _)| *j == i) {
+                Some((_, child)) => append_child(&mut stream, child),
+                None => {
+                    stream.append_empty_data();
+                }
+            }
+        }
+        stream.append_empty_data();
+        stream.out().to_vec()
+    }
+
+    fn extension(path: &[u8], child: &[u8]) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(2);
+        stream.append(&hex_prefix(path, false));
+        append_child(&mut stream, child);
+        stream.out().to_vec()
+    }
+
+    fn key(last_byte: u8) -> [u8; 32] {
+        let mut key = [0; 32];
+        key[31] = last_byte;
+        key
+    }
+
+    /// A small storage trie with the keys `0x00..01` and `0x00..02` set to 1 and 2.
+    /// The two leaves are embedded in the branch node, which is itself embedded in the root extension node,
+    /// so the whole trie is a single proof node.
+    fn small_trie() -> (H256, Vec<Bytes>) {
+        let branch = branch(&[(1, leaf(&[], &[1])), (2, leaf(&[], &[2]))]);
+        assert!(branch.len() < 32);
+        let root = extension(&[0; 63], &branch);
+        (H256(keccak256(&root)), vec![root.into()])
+    }
+
+    #[test]
+    fn insert_embedded_nodes() -> anyhow::Result<()> {
+        let (root, proof) = small_trie();
+        let mut trie = HashedPartialTrie::new(Node::Empty);
+        insert_proof(&mut trie, key(1), proof, true, &mut HashSet::new())?;
+        assert_eq!(trie.hash(), root);
+        assert_eq!(trie.get(Nibbles::from_bytes_be(&key(1))?), Some(&[1u8][..]));
+        assert_eq!(trie.get(Nibbles::from_bytes_be(&key(2))?), Some(&[2u8][..]));
+        Ok(())
+    }
+
+    #[test]
+    fn insert_embedded_nodes_exclusion() -> anyhow::Result<()> {
+        let (root, proof) = small_trie();
+        let mut trie = HashedPartialTrie::new(Node::Empty);
+        insert_proof(&mut trie, key(3), proof, false, &mut HashSet::new())?;
+        assert_eq!(trie.hash(), root);
+        assert_eq!(trie.get(Nibbles::from_bytes_be(&key(3))?), None);
+        Ok(())
+    }
+
+    /// Key whose nibbles start with the given ones, followed by zeros.
+    fn key_with_prefix(prefix: &[u8]) -> [u8; 32] {
+        let mut key = [0; 32];
+        for (i, &nibble) in prefix.iter().enumerate() {
+            key[i / 2] |= if i % 2 == 0 { nibble << 4 } else { nibble };
+        }
+        key
+    }
+
+    /// A leaf with a 32-byte value, which is too long to be embedded in its parent.
+    fn hashed_leaf(depth: usize, value: u8) -> Vec<u8> {
+        leaf(&[0; 64][depth..], &[value; 32])
+    }
+
+    /// Insert an exclusion proof for `key` in an empty partial trie, and check that the partial trie has the same root
+    /// as the full trie and doesn't contain the key.
+    fn check_exclusion(root: H256, key: [u8; 32], proof: Vec<Bytes>) -> anyhow::Result<()> {
+        assert_eq!(verify_proof(root, key, &proof)?, None);
+        let mut trie = HashedPartialTrie::new(Node::Empty);
+        insert_proof(&mut trie, key, proof, false, &mut HashSet::new())?;
+        assert_eq!(trie.hash(), root);
+        assert_eq!(trie.get(Nibbles::from_bytes_be(&key)?), None);
+        Ok(())
+    }
+
+    #[test]
+    fn exclusion_branch_with_empty_child() -> anyhow::Result<()> {
+        // Keys `0x10..` and `0x20..`.
+        let root_node = branch(&[(1, hashed_leaf(1, 1)), (2, hashed_leaf(1, 2))]);
+        let root = H256(keccak256(&root_node));
+        check_exclusion(root, key_with_prefix(&[3]), vec![root_node.into()])
+    }
+
+    #[test]
+    fn exclusion_diverging_leaf() -> anyhow::Result<()> {
+        // Keys `0x10..` and `0x20..`. The key `0x1f..` goes through the branch and ends at the leaf of `0x10..`.
+        let leaf = hashed_leaf(1, 1);
+        let root_node = branch(&[(1, leaf.clone()), (2, hashed_leaf(1, 2))]);
+        let root = H256(keccak256(&root_node));
+        check_exclusion(
+            root,
+            key_with_prefix(&[1, 0xf]),
+            vec![root_node.into(), leaf.into()],
+        )?;
+
+        // Single leaf at the root.
+        let root_node = hashed_leaf(0, 1);
+        let root = H256(keccak256(&root_node));
+        check_exclusion(root, key_with_prefix(&[0, 1]), vec![root_node.into()])
+    }
+
+    #[test]
+    fn exclusion_diverging_extension() -> anyhow::Result<()> {
+        // Keys `0x001..` and `0x002..`, under an extension node with path `00`.
+        let child = branch(&[(1, hashed_leaf(3, 1)), (2, hashed_leaf(3, 2))]);
+        let root_node = extension(&[0, 0], &child);
+        let root = H256(keccak256(&root_node));
+        check_exclusion(root, key_with_prefix(&[0, 1]), vec![root_node.into()])
+    }
+
+    #[test]
+    fn exclusion_empty_trie() -> anyhow::Result<()> {
+        check_exclusion(EMPTY_TRIE_HASH, key(1), vec![])?;
+        check_exclusion(EMPTY_TRIE_HASH, key(1), vec![vec![0x80].into()])?;
+        let mut trie = HashedPartialTrie::new(Node::Empty);
+        assert!(matches!(
+            insert_proof(
+                &mut trie,
+                key(1),
+                vec![vec![0x80].into()],
+                true,
+                &mut HashSet::new()
+            ),
+            Err(ProofInsertionError::UnexpectedEmptyNode { index: 0 })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn verify_embedded_nodes() {
+        let (root, proof) = small_trie();
+        assert_eq!(verify_proof(root, key(1), &proof).unwrap(), Some(vec![1]));
+        assert_eq!(verify_proof(root, key(2), &proof).unwrap(), Some(vec![2]));
+        assert_eq!(verify_proof(root, key(3), &proof).unwrap(), None);
+        assert!(matches!(
+            verify_proof(EMPTY_TRIE_HASH, key(1), &proof),
+            Err(ProofVerificationError::HashMismatch { index: 0, .. })
+        ));
+    }
+
+    /// Proofs in the format of an `eth_getProof` response, for the slots 0, 1, 2, 247476 and 515151 of a contract,
+    /// and an exclusion proof for the slot 3. The hashed keys of the slots 247476 and 515151 share their first 9 nibbles,
+    /// so both their leaves are embedded in their parent branch node.
+    /// The fixture is generated, not captured from a node: its state trie only holds the account.
+    #[test]
+    fn insert_storage_proofs_fixture() -> anyhow::Result<()> {
+        let response: EIP1186ProofResponse =
+            serde_json::from_str(include_str!("../testdata/storage_proof_embedded.json"))?;
+        let state_root = H256(keccak256(&response.account_proof[0]));
+        let account = verify_proof(
+            state_root,
+            keccak256(response.address.0),
+            &response.account_proof,
+        )?
+        .expect("The account exists");
+        let account = rlp::Rlp::new(&account);
+        assert_eq!(account.val_at::<H256>(2)?, response.storage_hash);
+        assert_eq!(account.val_at::<H256>(3)?, response.code_hash);
+        let mut trie = HashedPartialTrie::new(Node::Empty);
+        let mut dont_touch_these_nibbles = HashSet::new();
+        for sp in response.storage_proof {
+            let key = keccak256(sp.key.0);
+            let value = verify_proof(response.storage_hash, key, &sp.proof)?
+                .map(|v| rlp::decode::<U256>(&v))
+                .transpose()?
+                .unwrap_or_default();
+            assert_eq!(value, sp.value);
+            insert_proof(
+                &mut trie,
+                key,
+                sp.proof,
+                !sp.value.is_zero(),
+                &mut dont_touch_these_nibbles,
+            )?;
+        }
+        assert_eq!(trie.hash(), response.storage_hash);
+        Ok(())
+    }
+
+    #[test]
+    fn delete_collapsing_hash_node() -> anyhow::Result<()> {
+        // Keys `0x10..` and `0x20..`. Deleting `0x10..` collapses the root into the leaf of `0x20..`.
+        let sibling = hashed_leaf(1, 2);
+        let root_node = branch(&[(1, hashed_leaf(1, 1)), (2, sibling.clone())]);
+        let mut trie = HashedPartialTrie::new(Node::Empty);
+        let key = key_with_prefix(&[1]);
+        insert_proof(
+            &mut trie,
+            key,
+            vec![root_node.into(), hashed_leaf(1, 1).into()],
+            true,
+            &mut HashSet::new(),
+        )?;
+        let key = Nibbles::from_bytes_be(&key)?;
+        assert!(matches!(
+            delete_key(&mut trie, key),
+            Err(DeletionError::CollapsingHashNode { nibble: 2, .. })
+        ));
+
+        let mut prefix = Nibbles {
+            count: 0,
+            packed: U256::zero(),
+        };
+        prefix.push_nibble_back(2);
+        insert_node(&mut trie, prefix, &sibling, 0)?;
+        delete_key(&mut trie, key)?;
+        let collapsed = leaf(&key_nibbles(&key_with_prefix(&[2])), &[2; 32]);
+        assert_eq!(trie.hash(), H256(keccak256(collapsed)));
+        Ok(())
+    }
+
+    #[test]
+    fn hash_node_on_key_path() -> anyhow::Result<()> {
+        // Keys `0x10..` and `0x20..`, with only the proof of `0x10..` inserted.
+        let root_node = branch(&[(1, hashed_leaf(1, 1)), (2, hashed_leaf(1, 2))]);
+        let mut trie = HashedPartialTrie::new(Node::Empty);
+        let key = key_with_prefix(&[1]);
+        insert_proof(
+            &mut trie,
+            key,
+            vec![root_node.into(), hashed_leaf(1, 1).into()],
+            true,
+            &mut HashSet::new(),
+        )?;
+        assert_eq!(
+            hash_node_on_path(&trie, Nibbles::from_bytes_be(&key)?),
+            None
+        );
+        let (branch, nibble) =
+            hash_node_on_path(&trie, Nibbles::from_bytes_be(&key_with_prefix(&[2]))?)
+                .expect("The key goes through a hash node");
+        assert_eq!((branch.count, nibble), (0, 2));
+        assert_eq!(
+            hash_node_on_path(&trie, Nibbles::from_bytes_be(&key_with_prefix(&[3]))?),
+            None
+        );
+        Ok(())
+    }
+
+    /// Leaves of a reference trie, as the nibbles of their key and their value, sorted by key.
+    type Leaves = [(Vec<u8>, Vec<u8>)];
+
+    fn key_nibbles(key: &[u8; 32]) -> Vec<u8> {
+        key.iter().flat_map(|&b| [b >> 4, b & 0xf]).collect()
+    }
+
+    /// Number of nibbles shared by all the keys after the first `depth` ones.
+    fn common_prefix_len(leaves: &Leaves, depth: usize) -> usize {
+        let first = &leaves[0].0;
+        (depth..64)
+            .take_while(|&i| leaves.iter().all(|(k, _)| k[i] == first[i]))
+            .count()
+    }
+
+    /// Reference MPT encoding of the node at `depth` holding the given leaves, whose keys share their first `depth` nibbles.
+    fn encode_node(leaves: &Leaves, depth: usize) -> Vec<u8> {
+        match leaves {
+            [] => vec![0x80],
+            [(key, value)] => leaf(&key[depth..], value),
+            _ => {
+                let common = common_prefix_len(leaves, depth);
+                if common > 0 {
+                    let child = encode_node(leaves, depth + common);
+                    return extension(&leaves[0].0[depth..depth + common], &child);
+                }
+                let children = (0..16)
+                    .filter_map(|i| {
+                        let group = leaves
+                            .iter()
+                            .filter(|(k, _)| k[depth] == i)
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        (!group.is_empty()).then(|| (i as usize, encode_node(&group, depth + 1)))
+                    })
+                    .collect::<Vec<_>>();
+                branch(&children)
+            }
+        }
+    }
+
+    /// Reference MPT proof for `key`, i.e., the nodes on its path that are referenced by hash, starting from the root.
+    /// The proof of a key of the empty trie is empty.
+    fn prove(leaves: &Leaves, key: &[u8; 32]) -> Vec<Bytes> {
+        let key = key_nibbles(key);
+        let mut leaves = leaves.to_vec();
+        let mut depth = 0;
+        let mut proof = vec![];
+        while !leaves.is_empty() {
+            let node = encode_node(&leaves, depth);
+            // The node is embedded in its parent, and so are all its descendants.
+            if depth > 0 && node.len() < 32 {
+                break;
+            }
+            proof.push(node.into());
+            if leaves.len() == 1 {
+                break;
+            }
+            let common = common_prefix_len(&leaves, depth);
+            if common > 0 {
+                if key[depth..depth + common] != leaves[0].0[depth..depth + common] {
+                    break;
+                }
+                depth += common;
+            } else {
+                leaves.retain(|(k, _)| k[depth] == key[depth]);
+                depth += 1;
+            }
+        }
+        proof
+    }
+
+    /// Random keys. Half of them only differ in their last byte, so that they share long prefixes
+    /// and end in short leaves that get embedded in their parent.
+    fn arb_key() -> impl Strategy<Value = [u8; 32]> {
+        prop_oneof![any::<[u8; 32]>(), any::<u8>().prop_map(key)]
+    }
+
+    proptest! {
+        #[test]
+        fn insert_random_proofs(
+            leaves in prop::collection::btree_map(arb_key(), prop::collection::vec(any::<u8>(), 1..40), 0..64),
+            absent in prop::collection::vec(arb_key(), 0..8),
+            selected in prop::collection::vec(any::<bool>(), 64),
+        ) {
+            let reference = leaves
+                .iter()
+                .map(|(k, v)| (key_nibbles(k), v.clone()))
+                .collect::<Vec<_>>();
+            let root = H256(keccak256(encode_node(&reference, 0)));
+
+            let mut full_trie = HashedPartialTrie::new(Node::Empty);
+            for (k, v) in &leaves {
+                full_trie.insert(Nibbles::from_bytes_be(k).unwrap(), v.clone());
+            }
+            prop_assert_eq!(full_trie.hash(), root);
+
+            // Prove a random subset of the keys and some absent keys, in an order mixing inclusion and exclusion proofs.
+            let mut proven = leaves
+                .keys()
+                .zip(&selected)
+                .filter(|&(_, &s)| s)
+                .map(|(k, _)| *k)
+                .chain(absent.into_iter().filter(|k| !leaves.contains_key(k)))
+                .collect::<Vec<_>>();
+            proven.sort_by_key(keccak256);
+            proven.dedup();
+
+            let mut trie = HashedPartialTrie::new(Node::Empty);
+            let mut dont_touch_these_nibbles = HashSet::new();
+            for k in &proven {
+                let proof = prove(&reference, k);
+                let value = leaves.get(k);
+                prop_assert_eq!(verify_proof(root, *k, &proof).unwrap(), value.cloned());
+                insert_proof(&mut trie, *k, proof, value.is_some(), &mut dont_touch_these_nibbles)
+                    .unwrap();
+            }
+            if !proven.is_empty() {
+                prop_assert_eq!(trie.hash(), root);
+            }
+            for k in &proven {
+                let value = leaves.get(k).map(|v| &v[..]);
+                prop_assert_eq!(trie.get(Nibbles::from_bytes_be(k).unwrap()), value);
+            }
+        }
+
+        #[test]
+        fn delete_random_keys(
+            leaves in prop::collection::btree_map(arb_key(), prop::collection::vec(any::<u8>(), 1..40), 0..64),
+            absent in prop::collection::vec(arb_key(), 0..8),
+            selected in prop::collection::vec(any::<bool>(), 64),
+        ) {
+            let mut trie = HashedPartialTrie::new(Node::Empty);
+            for (k, v) in &leaves {
+                trie.insert(Nibbles::from_bytes_be(k).unwrap(), v.clone());
+            }
+            let deleted = leaves
+                .keys()
+                .zip(&selected)
+                .filter(|&(_, &s)| s)
+                .map(|(k, _)| *k)
+                .chain(absent)
+                .collect::<HashSet<_>>();
+            for k in &deleted {
+                delete_key(&mut trie, Nibbles::from_bytes_be(k).unwrap()).unwrap();
+            }
+
+            let remaining = leaves
+                .iter()
+                .filter(|(k, _)| !deleted.contains(*k))
+                .map(|(k, v)| (key_nibbles(k), v.clone()))
+                .collect::<Vec<_>>();
+            prop_assert_eq!(trie.hash(), H256(keccak256(encode_node(&remaining, 0))));
+        }
+    }
+}
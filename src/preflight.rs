@@ -0,0 +1,75 @@
+use ethers::prelude::*;
+use thiserror::Error;
+
+use crate::chain::ChainConfig;
+use crate::data_source::BlockDataSource;
+use crate::ProverOptions;
+
+/// A capability of the node required to prove a block, and found missing by `check_node`.
+#[derive(Debug, Error)]
+pub enum MissingCapability {
+    #[error("block 0 has no parent block to prove it from")]
+    Genesis,
+    #[error("block {0} is not available on the node: {1}")]
+    Block(u64, anyhow::Error),
+    #[error("block {0} is before the Shanghai upgrade on chain {1}, only post-Shanghai blocks are supported")]
+    PreShanghai(u64, U256),
+    #[error("the node does not support `debug_traceTransaction` with the prestate tracer: {0}")]
+    PrestateTracing(anyhow::Error),
+    #[error("the node does not support `debug_traceBlockByNumber` with the prestate tracer in diff mode, needed to prove one transaction at a time: {0}")]
+    StateDiffTracing(anyhow::Error),
+    #[error(
+        "the node cannot serve `eth_getProof` at the parent block {0}, its state may have been pruned: {1}"
+    )]
+    StateProofs(u64, anyhow::Error),
+}
+
+/// Check that the node supports everything needed to prove the given block with the given options, before starting to
+/// prove it. `source` should not be cached, so that cached responses can't hide a missing capability.
+pub async fn check_node<D: BlockDataSource>(
+    block_number: u64,
+    source: &D,
+    chain: &ChainConfig,
+    options: &ProverOptions,
+) -> Result<(), MissingCapability> {
+    let parent_number = block_number
+        .checked_sub(1)
+        .ok_or(MissingCapability::Genesis)?;
+    let block = source
+        .get_block(block_number.into())
+        .await
+        .map_err(|e| MissingCapability::Block(block_number, e))?;
+    if !chain.is_shanghai(&block) {
+        return Err(MissingCapability::PreShanghai(block_number, chain.chain_id));
+    }
+
+    if let Some(&hash) = block.transactions.first() {
+        source
+            .get_prestate_trace(hash)
+            .await
+            .map_err(MissingCapability::PrestateTracing)?;
+    }
+
+    if options.per_txn || options.predict_missing_nodes {
+        if let Err(e) = source.get_block_state_diffs(block_number.into()).await {
+            if options.per_txn {
+                return Err(MissingCapability::StateDiffTracing(e));
+            }
+            println!(
+                "The node can't trace the state diff of the block, missing trie nodes won't be predicted: {}",
+                e
+            );
+        }
+    }
+
+    source
+        .get_proof(
+            block.author.unwrap_or_default(),
+            vec![],
+            parent_number.into(),
+        )
+        .await
+        .map_err(|e| MissingCapability::StateProofs(parent_number, e))?;
+
+    Ok(())
+}
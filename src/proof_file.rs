@@ -0,0 +1,265 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::{ensure, Result};
+use ethers::prelude::*;
+use plonky2::field::types::PrimeField64;
+use plonky2_evm::proof::{BlockMetadata, PublicValues, TrieRoots};
+
+use crate::aggregation::{AggregatedBlockProof, RecursiveCircuits, RecursiveProof};
+
+/// Magic bytes at the start of a proof file.
+const MAGIC: &[u8; 8] = b"ETHPROOF";
+
+/// Version of the proof file format. Bump it whenever the layout of the file changes.
+pub const PROOF_FILE_VERSION: u32 = 2;
+
+/// Block proof stored on disk, along with the public values and metadata needed to check it without network access.
+///
+/// The file is laid out as follows, with all integers big-endian:
+/// - the magic bytes `ETHPROOF` and the version as a `u32`,
+/// - the block number as a `u64`,
+/// - the JSON encoding of the public values, prefixed with its length as a `u64`,
+/// - the Plonky2 encoding of the block proof, prefixed with its length as a `u64`.
+#[derive(Debug, Clone)]
+pub struct ProofFile {
+    pub block_number: u64,
+    /// Public values of the block proof.
+    pub public_values: PublicValues,
+    /// Serialized block proof. Deserializing it needs the common data of the block circuit.
+    pub proof: Vec<u8>,
+}
+
+impl ProofFile {
+    pub fn new(block_number: u64, proof: &AggregatedBlockProof) -> Self {
+        Self {
+            block_number,
+            public_values: proof.public_values.clone(),
+            proof: proof.proof.to_bytes(),
+        }
+    }
+
+    /// Read a proof file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        ensure!(&magic == MAGIC, "Not a proof file");
+        let version = u32::from_be_bytes(read_array(&mut reader)?);
+        ensure!(
+            version == PROOF_FILE_VERSION,
+            "Unsupported proof file version {}, expected {}",
+            version,
+            PROOF_FILE_VERSION
+        );
+        let block_number = u64::from_be_bytes(read_array(&mut reader)?);
+        let public_values = serde_json::from_slice(&read_bytes(&mut reader)?)?;
+        let proof = read_bytes(&mut reader)?;
+        Ok(Self {
+            block_number,
+            public_values,
+            proof,
+        })
+    }
+
+    /// Write the proof file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&PROOF_FILE_VERSION.to_be_bytes())?;
+        writer.write_all(&self.block_number.to_be_bytes())?;
+        write_bytes(&mut writer, &serde_json::to_vec(&self.public_values)?)?;
+        write_bytes(&mut writer, &self.proof)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Verify the block proof against the verifier data of the block circuit, and check that it proves the public
+    /// values recorded in the file.
+    pub fn verify(&self, circuits: &RecursiveCircuits) -> Result<()> {
+        let proof = RecursiveProof::from_bytes(self.proof.clone(), &circuits.block.circuit.common)?;
+        circuits.verify_block(&proof)?;
+
+        let pv = &self.public_values;
+        ensure!(
+            pv.block_metadata.block_number == self.block_number.into(),
+            "Public values are for block {}, expected {}",
+            pv.block_metadata.block_number,
+            self.block_number
+        );
+        let expected = public_inputs(pv)?;
+        let public_inputs = proof
+            .public_inputs
+            .iter()
+            .map(|x| x.to_canonical_u64())
+            .collect::<Vec<_>>();
+        ensure!(
+            public_inputs.starts_with(&expected),
+            "Block proof doesn't prove the public values of the proof file"
+        );
+        Ok(())
+    }
+}
+
+/// Public inputs of the block circuit for the given public values, without the verifier data appended after them.
+/// They are laid out as the public values targets of the branch: the trie roots before and after the block, each in the
+/// order state, transactions and receipts roots, then the block metadata. Hashes and the beneficiary are split into
+/// little-endian 32-bit limbs. The base fee takes two 32-bit limbs, and the other metadata fields a single one each.
+fn public_inputs(pv: &PublicValues) -> Result<Vec<u64>> {
+    let mut inputs = vec![];
+    for roots in [&pv.trie_roots_before, &pv.trie_roots_after] {
+        inputs.extend(trie_roots_limbs(roots));
+    }
+    inputs.extend(block_metadata_limbs(&pv.block_metadata)?);
+    Ok(inputs)
+}
+
+fn trie_roots_limbs(roots: &TrieRoots) -> Vec<u64> {
+    [
+        roots.state_root,
+        roots.transactions_root,
+        roots.receipts_root,
+    ]
+    .into_iter()
+    .flat_map(h256_limbs)
+    .collect()
+}
+
+fn block_metadata_limbs(metadata: &BlockMetadata) -> Result<Vec<u64>> {
+    let mut limbs =
+        u256_limbs(U256::from_big_endian(metadata.block_beneficiary.as_bytes()))[..5].to_vec();
+    for (name, value) in [
+        ("timestamp", metadata.block_timestamp),
+        ("number", metadata.block_number),
+        ("difficulty", metadata.block_difficulty),
+        ("gas limit", metadata.block_gaslimit),
+        ("chain id", metadata.block_chain_id),
+    ] {
+        ensure!(
+            value <= U256::from(u32::MAX),
+            "Block {} {} doesn't fit in the public inputs of the block circuit",
+            name,
+            value
+        );
+        limbs.push(value.as_u64());
+    }
+    let base_fee = metadata.block_base_fee;
+    ensure!(
+        base_fee <= U256::from(u64::MAX),
+        "Block base fee {} doesn't fit in the public inputs of the block circuit",
+        base_fee
+    );
+    limbs.extend(&u256_limbs(base_fee)[..2]);
+    Ok(limbs)
+}
+
+/// Limbs of a hash in the public inputs of the recursive circuits.
+fn h256_limbs(hash: H256) -> [u64; 8] {
+    u256_limbs(U256::from_big_endian(hash.as_bytes()))
+}
+
+/// Little-endian 32-bit limbs of a 256-bit integer.
+fn u256_limbs(value: U256) -> [u64; 8] {
+    let mut limbs = [0; 8];
+    for (i, limb) in value.0.into_iter().enumerate() {
+        limbs[2 * i] = limb & 0xffffffff;
+        limbs[2 * i + 1] = limb >> 32;
+    }
+    limbs
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_bytes(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let len = u64::from_be_bytes(read_array(reader)?);
+    let mut bytes = vec![];
+    reader.by_ref().take(len).read_to_end(&mut bytes)?;
+    ensure!(bytes.len() as u64 == len, "Truncated proof file");
+    Ok(bytes)
+}
+
+fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> Result<()> {
+    writer.write_all(&(bytes.len() as u64).to_be_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proof_file() -> ProofFile {
+        let roots = |i: u64| TrieRoots {
+            state_root: H256::from_low_u64_be(i),
+            transactions_root: H256::from_low_u64_be(i + 1),
+            receipts_root: H256::from_low_u64_be(i + 2),
+        };
+        ProofFile {
+            block_number: 17_000_000,
+            public_values: PublicValues {
+                trie_roots_before: roots(1),
+                trie_roots_after: roots(4),
+                block_metadata: BlockMetadata {
+                    block_beneficiary: Address::from_low_u64_be(0xbeef),
+                    block_timestamp: 1_681_338_455.into(),
+                    block_number: 17_000_000.into(),
+                    block_difficulty: 0.into(),
+                    block_gaslimit: 30_000_000.into(),
+                    block_chain_id: 1.into(),
+                    block_base_fee: 16_000_000_000u64.into(),
+                },
+            },
+            proof: (0..=255).collect(),
+        }
+    }
+
+    fn path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("{}-{}.proof", name, std::process::id()))
+    }
+
+    #[test]
+    fn save_and_load() {
+        let file = proof_file();
+        let path = path("proof-file-round-trip");
+        file.save(&path).unwrap();
+        let loaded = ProofFile::load(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(loaded.block_number, file.block_number);
+        assert_eq!(
+            serde_json::to_value(&loaded.public_values).unwrap(),
+            serde_json::to_value(&file.public_values).unwrap()
+        );
+        assert_eq!(loaded.proof, file.proof);
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() {
+        let path = path("proof-file-truncated");
+        proof_file().save(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let err = ProofFile::load(&path).unwrap_err();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(err.to_string(), "Truncated proof file");
+    }
+
+    #[test]
+    fn public_inputs_layout() {
+        let mut pv = proof_file().public_values;
+        let inputs = public_inputs(&pv).unwrap();
+        assert_eq!(inputs.len(), 2 * 3 * 8 + 5 + 5 + 2);
+        assert_eq!(inputs[..8], h256_limbs(H256::from_low_u64_be(1)));
+        assert_eq!(inputs[24..32], h256_limbs(H256::from_low_u64_be(4)));
+        assert_eq!(inputs[48..53], [0xbeef, 0, 0, 0, 0]);
+        assert_eq!(inputs[54], 17_000_000);
+        assert_eq!(inputs[58..], [16_000_000_000 & 0xffffffff, 3]);
+
+        pv.block_metadata.block_difficulty = U256::from(u32::MAX) + 1;
+        assert!(public_inputs(&pv).is_err());
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use ethers::prelude::*;
+use rand::{thread_rng, Rng};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, sleep_until, Instant};
+
+use crate::data_source::BlockDataSource;
+
+/// Retry and rate-limit policy for RPC calls.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts per call, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry. The delay doubles after every failed attempt.
+    pub initial_backoff: Duration,
+    /// Upper bound on the delay between two attempts.
+    pub max_backoff: Duration,
+    /// Maximum number of requests sent per second, or `None` for no limit.
+    pub requests_per_second: Option<f64>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            requests_per_second: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the given retry, with exponential backoff and jitter.
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self
+            .initial_backoff
+            .saturating_mul(1 << retry.min(16))
+            .min(self.max_backoff);
+        // Jitter in [delay/2, delay] so that concurrent requests don't all retry at the same time.
+        delay.mul_f64(thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// Return whether an error returned by a data source is transient and the call should be retried.
+/// Retries transport failures, timeouts and rate limiting, but not JSON-RPC errors like unsupported methods, nor
+/// responses that can't be deserialized into the expected type.
+fn is_transient(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<ProviderError>() {
+        Some(ProviderError::JsonRpcClientError(e)) => match e.as_error_response() {
+            Some(JsonRpcError { code, message, .. }) => {
+                let message = message.to_lowercase();
+                *code == 429
+                    || *code == -32005
+                    || message.contains("rate limit")
+                    || message.contains("timeout")
+                    || message.contains("timed out")
+                    || message == "header not found"
+            }
+            // A valid JSON response that doesn't have the expected shape won't have it on the next attempt either.
+            None if e.as_serde_error().is_some_and(|e| e.is_data()) => false,
+            // Not a JSON-RPC error response, e.g. an HTTP error or a non-JSON 429 body.
+            None => true,
+        },
+        Some(ProviderError::HTTPError(_)) => true,
+        _ => false,
+    }
+}
+
+/// Data source retrying the failed calls of an inner source and limiting its request rate.
+/// Clones share the rate limit, so that all the sources sending requests to the same node can be limited together.
+#[derive(Clone)]
+pub struct RetrySource<D> {
+    inner: D,
+    policy: RetryPolicy,
+    /// Earliest time at which the next request can be sent.
+    next_request: Arc<Mutex<Instant>>,
+}
+
+impl<D: BlockDataSource> RetrySource<D> {
+    pub fn new(inner: D, policy: RetryPolicy) -> Self {
+        Self {
+            inner,
+            policy,
+            next_request: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Wait until the rate limit allows sending a new request.
+    async fn throttle(&self) {
+        let Some(rps) = self.policy.requests_per_second else {
+            return;
+        };
+        let slot = {
+            let mut next_request = self.next_request.lock().await;
+            let slot = (*next_request).max(Instant::now());
+            *next_request = slot + Duration::from_secs_f64(1.0 / rps);
+            slot
+        };
+        sleep_until(slot).await;
+    }
+
+    /// Run the call `f` until it succeeds, it fails with a non-transient error, or the maximum number of attempts is reached.
+    async fn retry<T, F, Fut>(&self, method: &str, f: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            self.throttle().await;
+            match f().await {
+                Err(e) if attempt < self.policy.max_attempts && is_transient(&e) => {
+                    let backoff = self.policy.backoff(attempt - 1);
+                    println!(
+                        "{} failed (attempt {}/{}), retrying in {:?}: {}",
+                        method, attempt, self.policy.max_attempts, backoff, e
+                    );
+                    sleep(backoff).await;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<D: BlockDataSource> BlockDataSource for RetrySource<D> {
+    async fn get_chain_id(&self) -> Result<U256> {
+        self.retry("eth_chainId", || self.inner.get_chain_id())
+            .await
+    }
+
+    async fn get_block(&self, block_number: U64) -> Result<Block<TxHash>> {
+        self.retry("eth_getBlockByNumber", || {
+            self.inner.get_block(block_number)
+        })
+        .await
+    }
+
+    async fn get_block_with_txs(&self, block_number: U64) -> Result<Block<Transaction>> {
+        self.retry("eth_getBlockByNumber", || {
+            self.inner.get_block_with_txs(block_number)
+        })
+        .await
+    }
+
+    async fn get_transaction(&self, hash: TxHash) -> Result<Transaction> {
+        self.retry("eth_getTransactionByHash", || {
+            self.inner.get_transaction(hash)
+        })
+        .await
+    }
+
+    async fn get_transaction_receipt(&self, hash: TxHash) -> Result<TransactionReceipt> {
+        self.retry("eth_getTransactionReceipt", || {
+            self.inner.get_transaction_receipt(hash)
+        })
+        .await
+    }
+
+    async fn get_block_receipts(&self, block_number: U64) -> Result<Vec<TransactionReceipt>> {
+        self.retry("eth_getBlockReceipts", || {
+            self.inner.get_block_receipts(block_number)
+        })
+        .await
+    }
+
+    async fn get_prestate_trace(&self, hash: TxHash) -> Result<BTreeMap<Address, AccountState>> {
+        self.retry("debug_traceTransaction", || {
+            self.inner.get_prestate_trace(hash)
+        })
+        .await
+    }
+
+    async fn get_block_prestate_traces(
+        &self,
+        block_number: U64,
+    ) -> Result<Vec<BTreeMap<Address, AccountState>>> {
+        self.retry("debug_traceBlockByNumber", || {
+            self.inner.get_block_prestate_traces(block_number)
+        })
+        .await
+    }
+
+    async fn get_block_state_diffs(&self, block_number: U64) -> Result<Vec<DiffMode>> {
+        self.retry("debug_traceBlockByNumber", || {
+            self.inner.get_block_state_diffs(block_number)
+        })
+        .await
+    }
+
+    async fn get_proof(
+        &self,
+        address: Address,
+        locations: Vec<H256>,
+        block_number: U64,
+    ) -> Result<EIP1186ProofResponse> {
+        self.retry("eth_getProof", || {
+            self.inner
+                .get_proof(address, locations.clone(), block_number)
+        })
+        .await
+    }
+
+    async fn get_node(&self, hash: H256) -> Result<Bytes> {
+        self.retry("debug_dbGet", || self.inner.get_node(hash))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    /// Data source whose `get_chain_id` fails with the given error on its first `failures` calls.
+    #[derive(Clone)]
+    struct FlakySource {
+        calls: Arc<AtomicU32>,
+        failures: u32,
+        error: fn() -> anyhow::Error,
+    }
+
+    impl FlakySource {
+        fn new(failures: u32, error: fn() -> anyhow::Error) -> Self {
+            Self {
+                calls: Arc::new(AtomicU32::new(0)),
+                failures,
+                error,
+            }
+        }
+
+        fn calls(&self) -> u32 {
+            self.calls.load(Ordering::Relaxed)
+        }
+    }
+
+    #[async_trait]
+    impl BlockDataSource for FlakySource {
+        async fn get_chain_id(&self) -> Result<U256> {
+            if self.calls.fetch_add(1, Ordering::Relaxed) < self.failures {
+                Err((self.error)())
+            } else {
+                Ok(1.into())
+            }
+        }
+
+        async fn get_block(&self, _: U64) -> Result<Block<TxHash>> {
+            unimplemented!()
+        }
+
+        async fn get_block_with_txs(&self, _: U64) -> Result<Block<Transaction>> {
+            unimplemented!()
+        }
+
+        async fn get_transaction(&self, _: TxHash) -> Result<Transaction> {
+            unimplemented!()
+        }
+
+        async fn get_transaction_receipt(&self, _: TxHash) -> Result<TransactionReceipt> {
+            unimplemented!()
+        }
+
+        async fn get_block_receipts(&self, _: U64) -> Result<Vec<TransactionReceipt>> {
+            unimplemented!()
+        }
+
+        async fn get_prestate_trace(&self, _: TxHash) -> Result<BTreeMap<Address, AccountState>> {
+            unimplemented!()
+        }
+
+        async fn get_block_prestate_traces(
+            &self,
+            _: U64,
+        ) -> Result<Vec<BTreeMap<Address, AccountState>>> {
+            unimplemented!()
+        }
+
+        async fn get_block_state_diffs(&self, _: U64) -> Result<Vec<DiffMode>> {
+            unimplemented!()
+        }
+
+        async fn get_proof(
+            &self,
+            _: Address,
+            _: Vec<H256>,
+            _: U64,
+        ) -> Result<EIP1186ProofResponse> {
+            unimplemented!()
+        }
+
+        async fn get_node(&self, _: H256) -> Result<Bytes> {
+            unimplemented!()
+        }
+    }
+
+    fn json_rpc_error(code: i64, message: &str) -> anyhow::Error {
+        ProviderError::from(HttpClientError::JsonRpcError(JsonRpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }))
+        .into()
+    }
+
+    fn serde_error(text: &str) -> anyhow::Error {
+        let err = serde_json::from_str::<U256>(text).unwrap_err();
+        ProviderError::from(HttpClientError::SerdeJson {
+            err,
+            text: text.to_string(),
+        })
+        .into()
+    }
+
+    fn rate_limited() -> anyhow::Error {
+        json_rpc_error(-32005, "limit exceeded")
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            requests_per_second: None,
+        }
+    }
+
+    #[test]
+    fn transient_errors() {
+        assert!(is_transient(&rate_limited()));
+        assert!(is_transient(&json_rpc_error(429, "Too Many Requests")));
+        assert!(is_transient(&json_rpc_error(-32000, "header not found")));
+        assert!(is_transient(&json_rpc_error(-32000, "request timed out")));
+        // An HTML error page returned by a proxy.
+        assert!(is_transient(&serde_error("<html>502 Bad Gateway</html>")));
+        let request = reqwest::Client::new().get("http://[::1").build();
+        assert!(is_transient(
+            &ProviderError::HTTPError(request.unwrap_err()).into()
+        ));
+    }
+
+    #[test]
+    fn permanent_errors() {
+        assert!(!is_transient(&json_rpc_error(
+            -32601,
+            "the method debug_dbGet does not exist/is not available"
+        )));
+        assert!(!is_transient(&serde_error("{\"unexpected\": true}")));
+        assert!(!is_transient(&anyhow::anyhow!("Block not found")));
+    }
+
+    #[test]
+    fn backoff_stays_within_bounds() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            ..policy()
+        };
+        for retry in 0..40 {
+            let delay = Duration::from_millis(100 << retry.min(5)).min(policy.max_backoff);
+            let backoff = policy.backoff(retry);
+            assert!(delay / 2 <= backoff && backoff <= delay, "{:?}", backoff);
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors() {
+        let inner = FlakySource::new(3, rate_limited);
+        let source = RetrySource::new(inner.clone(), policy());
+        assert_eq!(source.get_chain_id().await.unwrap(), 1.into());
+        assert_eq!(inner.calls(), 4);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let inner = FlakySource::new(4, rate_limited);
+        let source = RetrySource::new(inner.clone(), policy());
+        assert!(source.get_chain_id().await.is_err());
+        assert_eq!(inner.calls(), 4);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_permanent_errors() {
+        let inner = FlakySource::new(1, || json_rpc_error(-32601, "method not found"));
+        let source = RetrySource::new(inner.clone(), policy());
+        assert!(source.get_chain_id().await.is_err());
+        assert_eq!(inner.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn clones_share_the_rate_limit() {
+        let policy = RetryPolicy {
+            requests_per_second: Some(50.0),
+            ..policy()
+        };
+        let source = RetrySource::new(FlakySource::new(0, rate_limited), policy);
+        let clone = source.clone();
+        let start = std::time::Instant::now();
+        for _ in 0..3 {
+            source.get_chain_id().await.unwrap();
+            clone.get_chain_id().await.unwrap();
+        }
+        // Six requests at 50 per second need at least five intervals of 20ms.
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
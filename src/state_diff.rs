@@ -0,0 +1,588 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use anyhow::anyhow;
+use eth_trie_utils::nibbles::Nibbles;
+use eth_trie_utils::partial_trie::{HashedPartialTrie, Node, PartialTrie};
+use ethers::prelude::*;
+use ethers::utils::keccak256;
+use ethers::utils::rlp::{self, Rlp, RlpStream};
+use plonky2_evm::generation::TrieInputs;
+
+use crate::errors::{MissingTrieNode, TrieKind, WitnessError};
+use crate::partial_tries::{
+    concat_nibbles, delete_key, hash_node_on_path, DeletionError, EMPTY_TRIE_HASH,
+};
+use crate::EMPTY_HASH;
+
+/// Storage slots set to zero by the given transactions, i.e., deleted from the storage tries, for each account.
+/// The prestate tracer in diff mode omits the slots set to zero from the post-state of an account.
+/// Accounts missing from the post-state altogether are deleted, and so is their whole storage trie.
+pub fn cleared_slots(diffs: &[DiffMode]) -> BTreeMap<Address, HashSet<H256>> {
+    let mut cleared = BTreeMap::<Address, HashSet<H256>>::new();
+    for diff in diffs {
+        for (address, pre) in &diff.pre {
+            let Some(post) = diff.post.get(address) else {
+                continue;
+            };
+            let post_storage = post.storage.clone().unwrap_or_default();
+            for (slot, value) in pre.storage.iter().flatten() {
+                if !value.is_zero() && !post_storage.contains_key(slot) {
+                    cleared.entry(*address).or_default().insert(*slot);
+                }
+            }
+        }
+    }
+    cleared
+}
+
+/// Accounts deleted by the given transactions, e.g. by `SELFDESTRUCT` or because they were touched while empty (EIP-161).
+/// The prestate tracer in diff mode omits them from the post-state.
+pub fn deleted_accounts(diffs: &[DiffMode]) -> HashSet<Address> {
+    diffs
+        .iter()
+        .flat_map(|diff| {
+            diff.pre
+                .keys()
+                .filter(|address| !diff.post.contains_key(*address))
+        })
+        .copied()
+        .collect()
+}
+
+/// Hash node left as the only child of a branch node collapsing because of deletions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CollapsingSibling {
+    /// Path of the collapsing branch node.
+    pub branch: Nibbles,
+    /// Nibble of the remaining child in the branch node.
+    pub nibble: u8,
+    /// Hash of the remaining child.
+    pub hash: H256,
+}
+
+/// Find the branch nodes of the trie that collapse when the `deleted` keys are removed from it, and that are left
+/// with a single child which is a hash node. Witness generation needs the preimage of that child to collapse the
+/// branch node.
+/// Insertions are not taken into account, so this can return nodes that end up not being needed.
+pub fn collapsing_siblings(
+    trie: &HashedPartialTrie,
+    deleted: &HashSet<Nibbles>,
+) -> Vec<CollapsingSibling> {
+    let mut siblings = vec![];
+    let empty_prefix = Nibbles {
+        count: 0,
+        packed: U256::zero(),
+    };
+    simulate_deletions(trie, empty_prefix, deleted, &mut siblings);
+    siblings
+}
+
+/// Simulate the removal of the `deleted` keys from the subtrie `node` at `prefix`, and return whether it is
+/// still non-empty afterwards. Hash nodes are assumed not to contain any deleted key.
+fn simulate_deletions(
+    node: &HashedPartialTrie,
+    prefix: Nibbles,
+    deleted: &HashSet<Nibbles>,
+    siblings: &mut Vec<CollapsingSibling>,
+) -> bool {
+    match &**node {
+        Node::Empty => false,
+        Node::Hash(_) => true,
+        Node::Leaf { nibbles, .. } => !deleted.contains(&concat_nibbles(prefix, *nibbles)),
+        Node::Extension { nibbles, child } => {
+            simulate_deletions(child, concat_nibbles(prefix, *nibbles), deleted, siblings)
+        }
+        Node::Branch { children, .. } => {
+            let remaining = (0..16)
+                .filter(|&i| {
+                    let mut child_prefix = prefix;
+                    child_prefix.push_nibble_back(i);
+                    simulate_deletions(&children[i as usize], child_prefix, deleted, siblings)
+                })
+                .collect::<Vec<_>>();
+            if let [i] = remaining[..] {
+                let child: &HashedPartialTrie = &children[i as usize];
+                if let Node::Hash(hash) = &**child {
+                    siblings.push(CollapsingSibling {
+                        branch: prefix,
+                        nibble: i,
+                        hash: *hash,
+                    });
+                }
+            }
+            !remaining.is_empty()
+        }
+    }
+}
+
+/// Fields of an account in the state trie.
+struct Account {
+    nonce: U256,
+    balance: U256,
+    storage_root: H256,
+    code_hash: H256,
+}
+
+impl Account {
+    /// Read the account of the given address from the state trie. Absent accounts are empty.
+    /// Fails with the missing trie node if the account is behind a hash node, as it can't be told apart from an absent
+    /// account otherwise.
+    fn get(state_trie: &HashedPartialTrie, address: Address) -> Result<Self, WitnessError> {
+        let key = Nibbles::from_bytes_be(&keccak256(address.0)).map_err(anyhow::Error::from)?;
+        if let Some((branch, nibble)) = hash_node_on_path(state_trie, key) {
+            return Err(WitnessError::MissingTrieNode(MissingTrieNode {
+                trie: TrieKind::State,
+                address,
+                slot: U256::zero(),
+                nibble,
+                depth: (64 - branch.count) as u8,
+            }));
+        }
+        Ok(match state_trie.get(key) {
+            Some(account) => {
+                let rlp = Rlp::new(account);
+                Self {
+                    nonce: rlp.val_at(0).map_err(anyhow::Error::from)?,
+                    balance: rlp.val_at(1).map_err(anyhow::Error::from)?,
+                    storage_root: rlp.val_at(2).map_err(anyhow::Error::from)?,
+                    code_hash: rlp.val_at(3).map_err(anyhow::Error::from)?,
+                }
+            }
+            None => Self {
+                nonce: U256::zero(),
+                balance: U256::zero(),
+                storage_root: EMPTY_TRIE_HASH,
+                code_hash: EMPTY_HASH,
+            },
+        })
+    }
+
+    /// Write the account of the given address in the state trie.
+    fn set(&self, state_trie: &mut HashedPartialTrie, address: Address) -> anyhow::Result<()> {
+        let key = Nibbles::from_bytes_be(&keccak256(address.0))?;
+        let mut stream = RlpStream::new_list(4);
+        stream
+            .append(&self.nonce)
+            .append(&self.balance)
+            .append(&self.storage_root)
+            .append(&self.code_hash);
+        state_trie.insert(key, stream.out().to_vec());
+        Ok(())
+    }
+}
+
+/// Apply the state changes made by a transaction to the partial tries, and add the deployed contract codes.
+/// All the accounts and storage slots changed by the transaction must be in the partial tries. A deletion can still need
+/// a trie node that is not in them, when it collapses a branch node whose remaining child is a hash node. The missing
+/// node is then returned.
+pub fn apply_state_diff(
+    tries: &mut TrieInputs,
+    contract_code: &mut HashMap<H256, Vec<u8>>,
+    diff: &DiffMode,
+) -> Result<(), WitnessError> {
+    for (address, post) in &diff.post {
+        let pre = diff.pre.get(address);
+        update_account(tries, contract_code, *address, pre, post)?;
+    }
+    for address in deleted_accounts(std::slice::from_ref(diff)) {
+        let key = keccak256(address.0);
+        delete(
+            &mut tries.state_trie,
+            TrieKind::State,
+            address,
+            key,
+            U256::zero(),
+        )?;
+        let address_hash = H256(key);
+        tries.storage_tries.retain(|(a, _)| *a != address_hash);
+    }
+    Ok(())
+}
+
+/// Add the given amounts to the balances of the given accounts, e.g. for the withdrawals of a block.
+/// Fails with the missing trie node if an account is behind a hash node.
+pub fn add_balances(
+    state_trie: &mut HashedPartialTrie,
+    amounts: &[(Address, U256)],
+) -> Result<(), WitnessError> {
+    for (address, amount) in amounts {
+        let mut account = Account::get(state_trie, *address)?;
+        account.balance += *amount;
+        account.set(state_trie, *address)?;
+    }
+    Ok(())
+}
+
+/// Update an account changed by a transaction. The diff mode of the prestate tracer only includes the changed fields in
+/// `post`, and omits the storage slots set to zero.
+fn update_account(
+    tries: &mut TrieInputs,
+    contract_code: &mut HashMap<H256, Vec<u8>>,
+    address: Address,
+    pre: Option<&AccountState>,
+    post: &AccountState,
+) -> Result<(), WitnessError> {
+    let mut account = Account::get(&tries.state_trie, address)?;
+    let written = post.storage.clone().unwrap_or_default();
+    let cleared = pre
+        .and_then(|pre| pre.storage.as_ref())
+        .into_iter()
+        .flatten()
+        .filter(|(slot, value)| !value.is_zero() && !written.contains_key(*slot))
+        .map(|(slot, _)| *slot)
+        .collect::<Vec<_>>();
+    if !written.is_empty() || !cleared.is_empty() {
+        let address_hash = H256(keccak256(address.0));
+        let index = match tries
+            .storage_tries
+            .iter()
+            .position(|(a, _)| *a == address_hash)
+        {
+            Some(index) => index,
+            None => {
+                if account.storage_root != EMPTY_TRIE_HASH {
+                    return Err(WitnessError::Other(anyhow!(
+                        "Storage trie of account {:?} is not in the witness",
+                        address
+                    )));
+                }
+                tries
+                    .storage_tries
+                    .push((address_hash, HashedPartialTrie::new(Node::Empty)));
+                tries.storage_tries.len() - 1
+            }
+        };
+        let trie = &mut tries.storage_tries[index].1;
+        for (slot, value) in &written {
+            let key = Nibbles::from_bytes_be(&keccak256(slot.0)).map_err(anyhow::Error::from)?;
+            trie.insert(key, rlp::encode(&U256::from_big_endian(&value.0)).to_vec());
+        }
+        for slot in cleared {
+            let slot_value = U256::from_big_endian(&slot.0);
+            delete(
+                trie,
+                TrieKind::Storage,
+                address,
+                keccak256(slot.0),
+                slot_value,
+            )?;
+        }
+        account.storage_root = trie.hash();
+    }
+    if let Some(balance) = post.balance {
+        account.balance = balance;
+    }
+    if let Some(nonce) = post.nonce {
+        account.nonce = nonce;
+    }
+    if let Some(code) = &post.code {
+        let code = hex::decode(&code[2..]).map_err(anyhow::Error::from)?;
+        account.code_hash = H256(keccak256(&code));
+        contract_code.insert(account.code_hash, code);
+    }
+    account.set(&mut tries.state_trie, address)?;
+    Ok(())
+}
+
+/// Delete a key from a partial trie. If a branch node collapses and its remaining child is a hash node, return it as
+/// the missing trie node, attributed to the given account and storage slot.
+fn delete(
+    trie: &mut HashedPartialTrie,
+    trie_kind: TrieKind,
+    address: Address,
+    key: [u8; 32],
+    slot: U256,
+) -> Result<(), WitnessError> {
+    let key = Nibbles::from_bytes_be(&key).map_err(anyhow::Error::from)?;
+    delete_key(trie, key).map_err(|e| match e {
+        DeletionError::CollapsingHashNode { branch, nibble } => {
+            WitnessError::MissingTrieNode(MissingTrieNode {
+                trie: trie_kind,
+                address,
+                slot,
+                nibble,
+                depth: (64 - branch.count) as u8,
+            })
+        }
+        e => WitnessError::Other(e.into()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn slot(i: u64) -> H256 {
+        H256::from_low_u64_be(i)
+    }
+
+    fn storage(slots: &[(u64, u64)]) -> Option<BTreeMap<H256, H256>> {
+        Some(slots.iter().map(|&(s, v)| (slot(s), slot(v))).collect())
+    }
+
+    fn diff(pre: &[(Address, AccountState)], post: &[(Address, AccountState)]) -> DiffMode {
+        DiffMode {
+            pre: pre.iter().cloned().collect(),
+            post: post.iter().cloned().collect(),
+        }
+    }
+
+    fn empty_tries() -> TrieInputs {
+        TrieInputs {
+            state_trie: Default::default(),
+            transactions_trie: Default::default(),
+            receipts_trie: Default::default(),
+            storage_tries: vec![],
+        }
+    }
+
+    fn nibbles(key: [u8; 32]) -> Nibbles {
+        Nibbles::from_bytes_be(&key).unwrap()
+    }
+
+    /// Root branch node with a hash node as the child at `nibble`.
+    fn branch_with_hash_node(nibble: u8, hash: H256) -> HashedPartialTrie {
+        let mut children: [_; 16] =
+            std::array::from_fn(|_| Arc::new(Box::new(HashedPartialTrie::new(Node::Empty))));
+        children[nibble as usize] = Arc::new(Box::new(HashedPartialTrie::new(Node::Hash(hash))));
+        HashedPartialTrie::new(Node::Branch {
+            children,
+            value: vec![],
+        })
+    }
+
+    #[test]
+    fn cleared_and_deleted() {
+        let (a, b) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let pre = AccountState {
+            storage: storage(&[(1, 5), (2, 0), (3, 7)]),
+            ..Default::default()
+        };
+        let post = AccountState {
+            storage: storage(&[(3, 8)]),
+            ..Default::default()
+        };
+        let diffs = [diff(&[(a, pre.clone()), (b, pre)], &[(a, post)])];
+        assert_eq!(
+            cleared_slots(&diffs),
+            BTreeMap::from([(a, HashSet::from([slot(1)]))])
+        );
+        assert_eq!(deleted_accounts(&diffs), HashSet::from([b]));
+    }
+
+    #[test]
+    fn collapsing_hash_node_sibling() {
+        let mut keys = [[0u8; 32]; 3];
+        keys[0][0] = 0x10;
+        keys[1][0] = 0x11;
+        keys[2][0] = 0x20;
+        let mut trie = HashedPartialTrie::new(Node::Empty);
+        for key in keys {
+            trie.insert(nibbles(key), vec![1]);
+        }
+        let hash = trie.hash();
+        // Replace the subtrie of `0x1..` with a hash node.
+        let Node::Branch { mut children, .. } = (*trie).clone() else {
+            panic!("The keys diverge at the first nibble");
+        };
+        let subtrie_hash = children[1].hash();
+        children[1] = Arc::new(Box::new(HashedPartialTrie::new(Node::Hash(subtrie_hash))));
+        let trie = HashedPartialTrie::new(Node::Branch {
+            children,
+            value: vec![],
+        });
+        assert_eq!(trie.hash(), hash);
+
+        assert_eq!(collapsing_siblings(&trie, &HashSet::new()), vec![]);
+        let deleted = HashSet::from([nibbles(keys[2])]);
+        let empty_prefix = Nibbles {
+            count: 0,
+            packed: U256::zero(),
+        };
+        assert_eq!(
+            collapsing_siblings(&trie, &deleted),
+            vec![CollapsingSibling {
+                branch: empty_prefix,
+                nibble: 1,
+                hash: subtrie_hash,
+            }]
+        );
+    }
+
+    #[test]
+    fn account_round_trip() -> anyhow::Result<()> {
+        let address = Address::repeat_byte(1);
+        let mut trie = HashedPartialTrie::new(Node::Empty);
+        let account = Account::get(&trie, address)?;
+        assert_eq!(
+            (
+                account.nonce,
+                account.balance,
+                account.storage_root,
+                account.code_hash
+            ),
+            (U256::zero(), U256::zero(), EMPTY_TRIE_HASH, EMPTY_HASH)
+        );
+        Account {
+            nonce: 1.into(),
+            balance: 2.into(),
+            storage_root: slot(3),
+            code_hash: slot(4),
+        }
+        .set(&mut trie, address)?;
+        let account = Account::get(&trie, address)?;
+        assert_eq!(
+            (
+                account.nonce,
+                account.balance,
+                account.storage_root,
+                account.code_hash
+            ),
+            (1.into(), 2.into(), slot(3), slot(4))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn apply_diffs() -> anyhow::Result<()> {
+        let address = Address::repeat_byte(1);
+        let mut tries = empty_tries();
+        let mut contract_code = HashMap::new();
+
+        // Deploy a contract writing two slots.
+        let post = AccountState {
+            balance: Some(10.into()),
+            nonce: Some(1.into()),
+            code: Some("0x6001".to_string()),
+            storage: storage(&[(1, 5), (2, 6)]),
+        };
+        let deployment = diff(&[(address, Default::default())], &[(address, post)]);
+        apply_state_diff(&mut tries, &mut contract_code, &deployment)?;
+        let code_hash = H256(keccak256([0x60, 0x01]));
+        assert_eq!(contract_code[&code_hash], vec![0x60, 0x01]);
+        let mut storage_trie = HashedPartialTrie::new(Node::Empty);
+        for (s, v) in [(1u64, 5u64), (2, 6)] {
+            storage_trie.insert(
+                nibbles(keccak256(slot(s).0)),
+                rlp::encode(&U256::from(v)).to_vec(),
+            );
+        }
+        let account = Account::get(&tries.state_trie, address)?;
+        assert_eq!(
+            (
+                account.nonce,
+                account.balance,
+                account.storage_root,
+                account.code_hash
+            ),
+            (1.into(), 10.into(), storage_trie.hash(), code_hash)
+        );
+
+        // Clear the first slot and overwrite the second one.
+        let pre = AccountState {
+            storage: storage(&[(1, 5), (2, 6)]),
+            ..Default::default()
+        };
+        let post = AccountState {
+            storage: storage(&[(2, 7)]),
+            ..Default::default()
+        };
+        apply_state_diff(
+            &mut tries,
+            &mut contract_code,
+            &diff(&[(address, pre.clone())], &[(address, post)]),
+        )?;
+        let mut storage_trie = HashedPartialTrie::new(Node::Empty);
+        storage_trie.insert(
+            nibbles(keccak256(slot(2).0)),
+            rlp::encode(&U256::from(7)).to_vec(),
+        );
+        assert_eq!(tries.storage_tries.len(), 1);
+        assert_eq!(tries.storage_tries[0].1.hash(), storage_trie.hash());
+        let account = Account::get(&tries.state_trie, address)?;
+        assert_eq!(
+            (account.balance, account.storage_root),
+            (10.into(), storage_trie.hash())
+        );
+
+        // Self-destruct.
+        apply_state_diff(
+            &mut tries,
+            &mut contract_code,
+            &diff(&[(address, pre)], &[]),
+        )?;
+        assert_eq!(tries.state_trie.hash(), EMPTY_TRIE_HASH);
+        assert!(tries.storage_tries.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn missing_storage_trie() {
+        let address = Address::repeat_byte(1);
+        let mut tries = empty_tries();
+        Account {
+            nonce: U256::zero(),
+            balance: U256::zero(),
+            storage_root: slot(1),
+            code_hash: EMPTY_HASH,
+        }
+        .set(&mut tries.state_trie, address)
+        .unwrap();
+        let post = AccountState {
+            storage: storage(&[(1, 1)]),
+            ..Default::default()
+        };
+        let result = apply_state_diff(
+            &mut tries,
+            &mut HashMap::new(),
+            &diff(&[(address, Default::default())], &[(address, post)]),
+        );
+        assert!(matches!(result, Err(WitnessError::Other(_))));
+    }
+
+    #[test]
+    fn add_withdrawal_balances() -> anyhow::Result<()> {
+        let (a, b) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let mut trie = HashedPartialTrie::new(Node::Empty);
+        add_balances(&mut trie, &[(a, 5.into())])?;
+        add_balances(&mut trie, &[(a, 3.into()), (b, 1.into())])?;
+        assert_eq!(Account::get(&trie, a)?.balance, 8.into());
+        assert_eq!(Account::get(&trie, b)?.balance, 1.into());
+        Ok(())
+    }
+
+    #[test]
+    fn account_behind_hash_node() {
+        let address = Address::repeat_byte(1);
+        let nibble = keccak256(address.0)[0] >> 4;
+        let mut trie = branch_with_hash_node(nibble, slot(1));
+        let expected = MissingTrieNode {
+            trie: TrieKind::State,
+            address,
+            slot: U256::zero(),
+            nibble,
+            depth: 64,
+        };
+        match add_balances(&mut trie, &[(address, 1.into())]) {
+            Err(WitnessError::MissingTrieNode(node)) => assert_eq!(node, expected),
+            r => panic!("Expected a missing trie node, got {:?}", r),
+        }
+
+        let mut tries = empty_tries();
+        tries.state_trie = trie;
+        let post = AccountState {
+            balance: Some(1.into()),
+            ..Default::default()
+        };
+        match apply_state_diff(
+            &mut tries,
+            &mut HashMap::new(),
+            &diff(&[(address, Default::default())], &[(address, post)]),
+        ) {
+            Err(WitnessError::MissingTrieNode(node)) => assert_eq!(node, expected),
+            r => panic!("Expected a missing trie node, got {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use anyhow::{anyhow, ensure, Context, Result};
+use eth_trie_utils::partial_trie::PartialTrie;
+use ethers::prelude::*;
+use ethers::utils::rlp;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use plonky2_evm::generation::{GenerationInputs, TrieInputs};
+
+use crate::aggregation::{prove_block_aggregated, AggregatedBlockProof, RecursiveCircuits};
+use crate::data_source::BlockDataSource;
+use crate::errors::WitnessError;
+use crate::state_diff::{add_balances, apply_state_diff};
+use crate::{
+    find_missing_node, generate_witness, get_block_inputs, prefetch_missing_nodes,
+    recover_missing_node, ProverOptions,
+};
+
+/// State of a block between two of its transactions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IntermediateState {
+    pub state_root: H256,
+    /// Gas used by the transactions of the block so far, as given by their receipts. It is not proven.
+    pub gas_used: U256,
+}
+
+/// Generation inputs of a single transaction of a block.
+#[derive(Clone, Debug)]
+pub struct TxnInputs {
+    pub inputs: GenerationInputs,
+    /// State left by the previous transactions of the block.
+    pub before: IntermediateState,
+    /// State after the transaction.
+    pub after: IntermediateState,
+}
+
+/// Split the generation inputs of a block into the inputs of each transaction, by applying the state `diffs` of the
+/// previous transactions to the partial tries. The withdrawals are processed with the last transaction.
+/// Fails if a transaction's gas limit exceeds the gas left according to the `receipts`, or on a missing trie node.
+pub fn split_block_inputs(
+    inputs: &GenerationInputs,
+    diffs: &[DiffMode],
+    receipts: &[TransactionReceipt],
+    final_hash: H256,
+) -> Result<Vec<TxnInputs>, WitnessError> {
+    let num_txns = inputs.signed_txns.len();
+    if diffs.len() != num_txns || receipts.len() != num_txns {
+        return Err(WitnessError::Other(anyhow!(
+            "Block has {} transactions, but {} state diffs and {} receipts",
+            num_txns,
+            diffs.len(),
+            receipts.len()
+        )));
+    }
+
+    let mut tries = TrieInputs {
+        state_trie: inputs.tries.state_trie.clone(),
+        transactions_trie: Default::default(),
+        receipts_trie: Default::default(),
+        storage_tries: inputs.tries.storage_tries.clone(),
+    };
+    let mut contract_code = inputs.contract_code.clone();
+    let mut before = IntermediateState {
+        state_root: tries.state_trie.hash(),
+        gas_used: U256::zero(),
+    };
+    let mut txns = vec![];
+    for i in 0..num_txns.max(1) {
+        if let Some(txn) = inputs.signed_txns.get(i) {
+            let gas_limit = rlp::decode::<Transaction>(txn)
+                .map_err(anyhow::Error::from)?
+                .gas;
+            let gas_left = inputs
+                .block_metadata
+                .block_gaslimit
+                .saturating_sub(before.gas_used);
+            if gas_limit > gas_left {
+                return Err(WitnessError::Other(anyhow!(
+                    "Transaction {} has gas limit {}, but only {} gas is left in the block",
+                    i,
+                    gas_limit,
+                    gas_left
+                )));
+            }
+        }
+        let txn_inputs = GenerationInputs {
+            signed_txns: inputs.signed_txns.get(i).cloned().into_iter().collect(),
+            tries: TrieInputs {
+                state_trie: tries.state_trie.clone(),
+                transactions_trie: inputs.tries.transactions_trie.clone(),
+                receipts_trie: inputs.tries.receipts_trie.clone(),
+                storage_tries: tries.storage_tries.clone(),
+            },
+            withdrawals: vec![],
+            contract_code: contract_code.clone(),
+            block_metadata: inputs.block_metadata.clone(),
+            addresses: vec![],
+        };
+        if let Some(diff) = diffs.get(i) {
+            apply_state_diff(&mut tries, &mut contract_code, diff)?;
+        }
+        let gas_used = receipts
+            .get(i)
+            .map_or(before.gas_used, |r| r.cumulative_gas_used);
+        let after = IntermediateState {
+            state_root: tries.state_trie.hash(),
+            gas_used,
+        };
+        txns.push(TxnInputs {
+            inputs: txn_inputs,
+            before,
+            after,
+        });
+        before = after;
+    }
+
+    let last = txns
+        .last_mut()
+        .expect("There is at least one set of inputs");
+    last.inputs.withdrawals = inputs.withdrawals.clone();
+    add_balances(&mut tries.state_trie, &inputs.withdrawals)?;
+    last.after.state_root = tries.state_trie.hash();
+    if last.after.state_root != final_hash {
+        return Err(WitnessError::Other(anyhow!(
+            "Applying the state diffs of the block gives the state root {:?}, expected {:?}",
+            last.after.state_root,
+            final_hash
+        )));
+    }
+    Ok(txns)
+}
+
+/// Get the receipts of the transactions of a block, in order.
+/// The receipts are fetched with a single `eth_getBlockReceipts` call, falling back to one `eth_getTransactionReceipt`
+/// call per transaction if the source doesn't support it.
+pub async fn get_block_receipts<D: BlockDataSource>(
+    block: &Block<TxHash>,
+    source: &D,
+    options: &ProverOptions,
+) -> Result<Vec<TransactionReceipt>> {
+    let block_number = block.number.context("Block has no number")?;
+    let receipts = match source.get_block_receipts(block_number).await {
+        Ok(receipts) => receipts,
+        Err(e) => {
+            println!(
+                "Fetching the block receipts failed, falling back to per-transaction receipts: {}",
+                e
+            );
+            stream::iter(&block.transactions)
+                .map(|&hash| source.get_transaction_receipt(hash))
+                .buffered(options.max_concurrent_proofs.max(1))
+                .try_collect::<Vec<_>>()
+                .await?
+        }
+    };
+    ensure!(
+        receipts
+            .iter()
+            .map(|r| r.transaction_hash)
+            .eq(block.transactions.iter().copied()),
+        "Receipts of block {} don't match its transactions",
+        block_number
+    );
+    let gas_used = receipts
+        .last()
+        .map_or(U256::zero(), |r| r.cumulative_gas_used);
+    ensure!(
+        gas_used == block.gas_used,
+        "Receipts of block {} use {} gas, expected {}",
+        block_number,
+        gas_used,
+        block.gas_used
+    );
+    Ok(receipts)
+}
+
+/// Prove an Ethereum block one transaction at a time, which needs much less memory than proving it at once.
+/// The generation inputs of each transaction are derived from the ones of the block with the state diff of the block.
+/// When witness generation of a transaction fails because of a missing trie node, the node is inserted in the partial
+/// tries of the block, the block is split again, and witness generation resumes at the failing transaction.
+/// If `options.full_proof` is set, once witness generation succeeds for all transactions, prove them with `circuits` and
+/// aggregate their proofs into a single block proof.
+pub async fn prove_block_txns_loop<D: BlockDataSource>(
+    block_number: u64,
+    source: &D,
+    options: &ProverOptions,
+    circuits: Option<&RecursiveCircuits>,
+) -> Result<Option<AggregatedBlockProof>> {
+    let (mut inputs, block, receipts) = get_block_inputs(block_number, source, options).await?;
+    let final_hash = block.state_root;
+    if options.predict_missing_nodes {
+        prefetch_missing_nodes(block_number, &mut inputs, source, options).await?;
+    }
+    let diffs = source
+        .get_block_state_diffs(block_number.into())
+        .await
+        .context("Per-transaction proving needs the state diff of the block")?;
+
+    let mut done = 0;
+    let txns = loop {
+        let error = match split_block_inputs(&inputs, &diffs, &receipts, final_hash) {
+            Ok(txns) => match check_txns(&txns[done..], &mut done) {
+                Ok(()) => break txns,
+                Err(e) => e,
+            },
+            Err(e) => e,
+        };
+        let node = find_missing_node(block_number, &mut inputs, error, source, options).await?;
+        println!("Block number: {}, txn: {}, {}", block_number, done, node);
+        recover_missing_node(block_number, &mut inputs, &node, source, options).await?;
+    };
+    if !options.full_proof {
+        return Ok(None);
+    }
+    let circuits = circuits.context("Proving the transactions needs the recursive circuits")?;
+    let proof = prove_block_aggregated(circuits, txns)?;
+    ensure!(
+        proof.after.state_root == final_hash,
+        "Block proof has final state root {:?}, expected {:?}",
+        proof.after.state_root,
+        final_hash
+    );
+    Ok(Some(proof))
+}
+
+/// Run witness generation on each transaction, and check the state root after it.
+/// `done` is incremented for each transaction that succeeds.
+fn check_txns(txns: &[TxnInputs], done: &mut usize) -> Result<(), WitnessError> {
+    for txn in txns {
+        let pv = generate_witness(txn.inputs.clone())?;
+        let state_root = pv.trie_roots_after.state_root;
+        if state_root != txn.after.state_root {
+            return Err(WitnessError::Other(anyhow!(
+                "Transaction {} has state root {:?} after witness generation, expected {:?}",
+                *done,
+                state_root,
+                txn.after.state_root
+            )));
+        }
+        *done += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use eth_trie_utils::partial_trie::HashedPartialTrie;
+    use plonky2_evm::proof::BlockMetadata;
+
+    use super::*;
+
+    fn signed_txn(gas: u64) -> Vec<u8> {
+        Transaction {
+            gas: gas.into(),
+            v: 27.into(),
+            r: 1.into(),
+            s: 1.into(),
+            ..Default::default()
+        }
+        .rlp()
+        .to_vec()
+    }
+
+    fn block_inputs(gas_limits: &[u64], block_gaslimit: u64) -> GenerationInputs {
+        GenerationInputs {
+            signed_txns: gas_limits.iter().map(|&gas| signed_txn(gas)).collect(),
+            tries: TrieInputs {
+                state_trie: Default::default(),
+                transactions_trie: Default::default(),
+                receipts_trie: Default::default(),
+                storage_tries: vec![],
+            },
+            withdrawals: vec![(Address::repeat_byte(3), 3.into())],
+            contract_code: HashMap::new(),
+            block_metadata: BlockMetadata {
+                block_beneficiary: Address::zero(),
+                block_timestamp: U256::zero(),
+                block_number: U256::one(),
+                block_difficulty: U256::zero(),
+                block_gaslimit: block_gaslimit.into(),
+                block_chain_id: U256::one(),
+                block_base_fee: U256::zero(),
+            },
+            addresses: vec![],
+        }
+    }
+
+    /// Diff of a transaction crediting `amount` to a new account.
+    fn credit(address: Address, amount: u64) -> DiffMode {
+        DiffMode {
+            pre: BTreeMap::from([(address, Default::default())]),
+            post: BTreeMap::from([(
+                address,
+                AccountState {
+                    balance: Some(amount.into()),
+                    ..Default::default()
+                },
+            )]),
+        }
+    }
+
+    fn receipt(cumulative_gas_used: u64) -> TransactionReceipt {
+        TransactionReceipt {
+            cumulative_gas_used: cumulative_gas_used.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn split_block() -> Result<()> {
+        let (a, b) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let inputs = block_inputs(&[21_000, 21_000], 100_000);
+        let mut final_trie = HashedPartialTrie::default();
+        add_balances(&mut final_trie, &[(a, 1.into()), (b, 2.into())])?;
+        add_balances(&mut final_trie, &inputs.withdrawals)?;
+
+        let txns = split_block_inputs(
+            &inputs,
+            &[credit(a, 1), credit(b, 2)],
+            &[receipt(21_000), receipt(42_000)],
+            final_trie.hash(),
+        )?;
+        assert_eq!(txns.len(), 2);
+        assert_eq!(
+            txns[0].before.state_root,
+            HashedPartialTrie::default().hash()
+        );
+        assert_eq!(txns[0].after, txns[1].before);
+        assert_eq!(txns[1].after.gas_used, 42_000.into());
+        assert_eq!(
+            txns[1].inputs.tries.state_trie.hash(),
+            txns[1].before.state_root
+        );
+        for (i, txn) in txns.iter().enumerate() {
+            assert_eq!(txn.inputs.signed_txns, vec![inputs.signed_txns[i].clone()]);
+        }
+        assert!(txns[0].inputs.withdrawals.is_empty());
+        assert_eq!(txns[1].inputs.withdrawals, inputs.withdrawals);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_gas_limit_above_gas_left() {
+        let (a, b) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let inputs = block_inputs(&[21_000, 21_000], 30_000);
+        match split_block_inputs(
+            &inputs,
+            &[credit(a, 1), credit(b, 2)],
+            &[receipt(21_000), receipt(42_000)],
+            H256::zero(),
+        ) {
+            Err(WitnessError::Other(e)) => assert_eq!(
+                e.to_string(),
+                "Transaction 1 has gas limit 21000, but only 9000 gas is left in the block"
+            ),
+            r => panic!("Expected the gas limit to be rejected, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn rejects_missing_diffs() {
+        let inputs = block_inputs(&[21_000, 21_000], 100_000);
+        let result = split_block_inputs(
+            &inputs,
+            &[credit(Address::repeat_byte(1), 1)],
+            &[receipt(21_000), receipt(42_000)],
+            H256::zero(),
+        );
+        assert!(matches!(result, Err(WitnessError::Other(_))));
+    }
+
+    #[test]
+    fn rejects_wrong_final_state_root() {
+        let inputs = block_inputs(&[21_000], 100_000);
+        let result = split_block_inputs(
+            &inputs,
+            &[credit(Address::repeat_byte(1), 1)],
+            &[receipt(21_000)],
+            H256::zero(),
+        );
+        assert!(matches!(result, Err(WitnessError::Other(_))));
+    }
+}
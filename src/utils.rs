@@ -1,7 +1,23 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
 use flexi_logger::Logger;
+use serde::Serialize;
 
 pub fn init_env_logger() {
     let _ = Logger::try_with_env_or_str("plonky2::util::timing=info")
         .unwrap()
         .start();
 }
+
+/// Write `value` as JSON to `path`. The value is written to a temporary file first, and then moved to `path`, so that
+/// concurrent runs never read a partially written file.
+pub fn write_json_file<T: Serialize>(path: &Path, value: &T) -> anyhow::Result<()> {
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    serde_json::to_writer(&mut writer, value)?;
+    writer.flush()?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}